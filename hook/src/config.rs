@@ -0,0 +1,50 @@
+//! Per-target configuration handed over by the injector through `hook_entry`
+//!
+//! The payload is UTF-8 text, one `key = value` per line, `#` starts a comment.
+//! Hooks read their settings when they run, so a configuration arriving after the
+//! hooks were installed by the constructor still applies.
+
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Status `hook_entry` returns to the injector
+pub const ENTRY_OK: i32 = 0;
+pub const ENTRY_NOT_UTF8: i32 = 1;
+pub const ENTRY_BAD_LINE: i32 = 2;
+pub const ENTRY_UNKNOWN_KEY: i32 = 3;
+
+/// Every key a hook understands
+const KEYS: [&str; 4] = ["fileio.path_contains", "fileio.path_suffix", "fileio.key", "fileio.value"];
+
+static CONFIG: Lazy<RwLock<HashMap<String, String>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Replace the configuration with `payload`, nothing changes unless all of it is valid
+pub fn load(payload: &[u8]) -> i32 {
+    let Ok(text) = std::str::from_utf8(payload) else {
+        return ENTRY_NOT_UTF8;
+    };
+
+    let mut values = HashMap::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return ENTRY_BAD_LINE;
+        };
+        let key = key.trim();
+        if !KEYS.contains(&key) {
+            return ENTRY_UNKNOWN_KEY;
+        }
+        values.insert(key.to_string(), value.trim().to_string());
+    }
+
+    *CONFIG.write().unwrap() = values;
+    ENTRY_OK
+}
+
+/// Value of `key`, or `default` when the injector didn't set it
+pub fn get(key: &str, default: &str) -> String {
+    CONFIG.read().unwrap().get(key).cloned().unwrap_or_else(|| default.to_string())
+}
@@ -10,10 +10,10 @@ pub fn find_got_entry_for_symbol(path: &str, symbol: &str) -> Option<u64> {
     let elf = Elf::parse(&buffer).ok()?;
 
     // Handle both REL and RELA
-    for (_i, rel) in elf.dynrels.iter().chain(elf.pltrelocs.iter()).enumerate() {
+    for rel in elf.dynrels.iter().chain(elf.pltrelocs.iter()) {
         let sym_idx = rel.r_sym;
         if let Some(sym) = elf.dynsyms.get(sym_idx) {
-            if let Some(name) = elf.dynstrtab.get_at(sym.st_name).map(|r| r) {
+            if let Some(name) = elf.dynstrtab.get_at(sym.st_name) {
                 logd!("[*] Relocation symbol '{}' at offset 0x{:x}", name, rel.r_offset);
                 if name == symbol {
                     logd!("[*] Found GOT entry for '{}' at offset 0x{:x}", symbol, rel.r_offset);
@@ -35,7 +35,7 @@ pub fn find_module_base(name: &str) -> Option<usize> {
     let f = File::open("/proc/self/maps").ok()?;
     let reader = BufReader::new(f);
 
-    for line in reader.lines().flatten() {
+    for line in reader.lines().map_while(Result::ok) {
         if line.contains(name) {
             logd!("[*] match for {} -> {}", name, line);
             let addr = line.split('-').next()?;
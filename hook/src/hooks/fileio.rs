@@ -6,6 +6,7 @@ use std::sync::Mutex;
 use std::slice;
 use memchr::memmem;
 
+use crate::config;
 use crate::elf::{find_got_entry_for_symbol, find_module_base};
 use crate::logd;
 use crate::patch::patch_got_entry;
@@ -70,7 +71,7 @@ pub unsafe fn hook_file_io() {
     logd!("[*] GOT after: {:?}", *fopen_got_ptr);
 
     logd!("[+] fopen GOT patch applied: {:#x} -> {:?}", fopen_got_offset, hooked_fopen as *const c_void);
-    REAL_FOPEN = Some(std::mem::transmute(fopen_orig));
+    REAL_FOPEN = Some(std::mem::transmute::<*const c_void, FopenType>(fopen_orig));
 
     logd!("[*] GOT entry for 'fopen' offset = 0x{:x}", fopen_got_offset);
     logd!("[*] Base of current process = 0x{:x}", base);
@@ -88,7 +89,7 @@ pub unsafe fn hook_file_io() {
     logd!("[*] GOT after: {:?}", *fread_got_ptr);
 
     logd!("[+] fread GOT patch applied: {:#x} -> {:?}", fread_got_offset, hooked_fread as *const c_void);
-    REAL_FREAD = Some(std::mem::transmute(fread_orig));
+    REAL_FREAD = Some(std::mem::transmute::<*const c_void, FreadType>(fread_orig));
 
     logd!("[*] GOT entry for 'fread' offset = 0x{:x}", fread_got_offset);
     logd!("[*] Base of current process = 0x{:x}", base);
@@ -96,7 +97,7 @@ pub unsafe fn hook_file_io() {
 }
 
 thread_local! {
-    static IN_FOPEN_HOOK: std::cell::Cell<bool> = std::cell::Cell::new(false);
+    static IN_FOPEN_HOOK: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
 }
 
 #[no_mangle]
@@ -116,8 +117,8 @@ pub unsafe extern "C" fn hooked_fopen(path: *const c_char, mode: *const c_char)
 
     logd!("[+] fopen checking: {} w/ {} ", path_str, mode_str);
     if !file.is_null()
-        && path_str.contains("/data/local/temp/")
-        && path_str.ends_with(".target")
+        && path_str.contains(config::get("fileio.path_contains", "/data/local/temp/").as_str())
+        && path_str.ends_with(config::get("fileio.path_suffix", ".target").as_str())
     {
         logd!("[+] fopen matched: {}", path_str);
         TRACKED_FILES.lock().unwrap().insert(UnsafeFilePtr(file));
@@ -170,8 +171,10 @@ pub unsafe extern "C" fn hooked_fread(
     let mut modified = false;
 
     // If this specific phrase is sound, change it's value
-    // current target is secret_key="{anything}"
-    let key = b"secret_key=\"";
+    // default target is secret_key="{anything}"
+    let key = format!("{}=\"", config::get("fileio.key", "secret_key"));
+    let key = key.as_bytes();
+    let value = config::get("fileio.value", "d1ff");
     let mut key_matches = vec![];
     for pos in memmem::find_iter(buf, key) {
         let val_start = pos + key.len();
@@ -184,11 +187,10 @@ pub unsafe extern "C" fn hooked_fread(
         }
     }
     for (start, end) in key_matches {
-        if &buf[start..end] != b"0" {
+        // the value is patched in place, so it has to keep its length
+        if &buf[start..end] != b"0" && end - start == value.len() {
             logd!("[patch] {:?} found: {}..{} → patching", key, start, end);
-            // Note we're cheating a bit here with the a potentially unsafe copy into
-            // the quotes
-            buf[start..end].copy_from_slice(b"d1ff");
+            buf[start..end].copy_from_slice(value.as_bytes());
             modified = true;
         }
     }
@@ -1,16 +1,13 @@
 use std::ffi::CString;
 
+use crate::log::{__android_log_print, ANDROID_LOG_INFO};
+
 pub unsafe fn init() {
     log_injection();
 }
 
 pub unsafe fn log_injection() {
     // Use direct Android logging that works in release mode
-    const ANDROID_LOG_INFO: i32 = 4;
-    extern "C" {
-        fn __android_log_print(prio: i32, tag: *const std::os::raw::c_char, fmt: *const std::os::raw::c_char, ...) -> i32;
-    }
-
     let tag = CString::new("HOOK_INJECT").expect("CString::new failed");
     let fmt = CString::new("%s").unwrap();
 
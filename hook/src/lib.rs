@@ -2,6 +2,7 @@ mod hooks;
 mod patch;
 mod log;
 mod elf;
+mod config;
 
 pub fn exposed_function() {
     crate::hooks::init();
@@ -12,3 +13,18 @@ unsafe fn ctor_init() {
     logd!("Running init");
     exposed_function();
 }
+
+/// Entry point the injector calls after loading us, with a configuration payload
+/// (see `config`). Returns one of the `config::ENTRY_*` statuses
+///
+/// # Safety
+/// `payload` has to point to `len` readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn hook_entry(payload: *const u8, len: usize) -> i32 {
+    let payload = if payload.is_null() || len == 0 { &[][..] } else { std::slice::from_raw_parts(payload, len) };
+    let status = config::load(payload);
+    logd!("hook_entry: {} bytes of configuration, status {}", len, status);
+    // no-op when the constructor got to it first
+    exposed_function();
+    status
+}
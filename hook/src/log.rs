@@ -1,26 +1,34 @@
+use std::os::raw::{c_char, c_int};
+
+#[cfg(debug_assertions)]
+pub const ANDROID_LOG_DEBUG: c_int = 3;
+pub const ANDROID_LOG_INFO: c_int = 4;
+
+// liblog, declared once for `logd!` and the hooks logging on their own
+extern "C" {
+    pub fn __android_log_print(prio: c_int, tag: *const c_char, fmt: *const c_char, ...) -> c_int;
+}
+
 #[macro_export]
 macro_rules! logd {
     ($($arg:tt)*) => {{
         #[cfg(debug_assertions)]
         {
-            const ANDROID_LOG_DEBUG: i32 = 3;
             use std::ffi::CString;
-            extern "C" {
-                fn __android_log_print(prio: i32, tag: *const i8, fmt: *const i8, ...) -> i32;
-            }
+            use $crate::log::{__android_log_print, ANDROID_LOG_DEBUG};
 
             let tag = CString::new("hook").expect("CString::new failed");
             let msg = format!($($arg)*);
             if let Ok(c_msg) = CString::new(msg) {
                 let fmt = CString::new("%s").unwrap();
                 unsafe {
-                    __android_log_print(ANDROID_LOG_DEBUG, tag.as_ptr() as *const i8, fmt.as_ptr() as *const i8, c_msg.as_ptr());
+                    __android_log_print(ANDROID_LOG_DEBUG, tag.as_ptr(), fmt.as_ptr(), c_msg.as_ptr());
                 }
             } else {
                 let fallback = CString::new("logd! message contained null byte").unwrap();
                 let fmt = CString::new("%s").unwrap();
                 unsafe {
-                    __android_log_print(ANDROID_LOG_DEBUG, tag.as_ptr() as *const i8, fmt.as_ptr() as *const i8, fallback.as_ptr());
+                    __android_log_print(ANDROID_LOG_DEBUG, tag.as_ptr(), fmt.as_ptr(), fallback.as_ptr());
                 }
             }
         }
@@ -14,11 +14,12 @@ pub unsafe fn patch_got_entry(addr: *mut *const c_void, replacement: *const c_vo
 
     let original = *addr;
 
-    if cfg!(target_arch = "aarch64") {
-        *addr = replacement;
-    } else {
+    if cfg!(target_arch = "arm") {
         // For 32-bit ARM, set the Thumb bit
         *addr = (replacement as usize | 1) as *const c_void;
+    } else {
+        // aarch64, x86 and x86_64 take plain function pointers
+        *addr = replacement;
     }
 
     mprotect(page as *mut c_void, page_size, PROT_READ | PROT_EXEC);
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::fs;
+
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::section_header::SHN_UNDEF;
+use goblin::elf::sym::{Sym, STT_GNU_IFUNC};
+use goblin::elf::Elf;
+
+use crate::utils::get_module_mapping;
+
+/// The hash function DT_GNU_HASH tables are built with
+pub fn gnu_hash(name: &str) -> u32 {
+    name.bytes().fold(5381u32, |h, c| h.wrapping_mul(33).wrapping_add(c as u32))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    bytes.get(offset..offset + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+/// Only defined symbols with the default version are something we can call
+fn is_usable(elf: &Elf, idx: usize, sym: &Sym) -> bool {
+    let hidden = elf
+        .versym
+        .as_ref()
+        .and_then(|v| v.get_at(idx))
+        .map(|v| v.is_hidden())
+        .unwrap_or(false);
+    sym.st_shndx != SHN_UNDEF as usize && sym.st_value != 0 && !hidden
+}
+
+fn name_matches(elf: &Elf, sym: &Sym, name: &str) -> bool {
+    elf.dynstrtab.get_at(sym.st_name) == Some(name)
+}
+
+/// Walk the DT_GNU_HASH table the same way the dynamic linker does
+fn gnu_hash_lookup(elf: &Elf, bytes: &[u8], name: &str) -> Option<Sym> {
+    let table = elf.dynamic.as_ref()?.info.gnu_hash? as usize;
+
+    let nbuckets = read_u32(bytes, table)? as usize;
+    let symoffset = read_u32(bytes, table + 4)? as usize;
+    let bloom_size = read_u32(bytes, table + 8)? as usize;
+    if nbuckets == 0 {
+        return None;
+    }
+
+    let bloom_word = if elf.is_64 { 8 } else { 4 };
+    let buckets = table + 16 + bloom_size * bloom_word;
+    let chains = buckets + nbuckets * 4;
+
+    let hash = gnu_hash(name);
+    let mut idx = read_u32(bytes, buckets + (hash as usize % nbuckets) * 4)? as usize;
+    if idx < symoffset {
+        return None;
+    }
+
+    loop {
+        let chain_hash = read_u32(bytes, chains + (idx - symoffset) * 4)?;
+        if (chain_hash | 1) == (hash | 1) {
+            if let Some(sym) = elf.dynsyms.get(idx) {
+                if name_matches(elf, &sym, name) && is_usable(elf, idx, &sym) {
+                    return Some(sym);
+                }
+            }
+        }
+        // lowest bit marks the end of this bucket's chain
+        if chain_hash & 1 != 0 {
+            return None;
+        }
+        idx += 1;
+    }
+}
+
+/// Find an exported symbol in `.dynsym`, through GNU hash when the module has one
+pub fn find_dynamic_symbol(elf: &Elf, bytes: &[u8], name: &str) -> Option<Sym> {
+    if elf.dynamic.as_ref().and_then(|d| d.info.gnu_hash).is_some() {
+        return gnu_hash_lookup(elf, bytes, name);
+    }
+
+    // SysV hash only (or none at all), a linear scan is good enough
+    elf.dynsyms
+        .iter()
+        .enumerate()
+        .find(|(idx, sym)| name_matches(elf, sym, name) && is_usable(elf, *idx, sym))
+        .map(|(_, sym)| sym)
+}
+
+/// Find a symbol the module keeps to itself in `.symtab`, which only modules that
+/// weren't fully stripped still have
+pub fn find_internal_symbol(elf: &Elf, name: &str) -> Option<Sym> {
+    elf.syms
+        .iter()
+        .find(|sym| sym.st_shndx != SHN_UNDEF as usize && sym.st_value != 0 && elf.strtab.get_at(sym.st_name) == Some(name))
+}
+
+/// Difference between where the module got mapped and the addresses it was linked at
+pub fn load_bias(elf: &Elf, mapped_base: u64) -> u64 {
+    let first_vaddr = elf
+        .program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .map(|ph| ph.p_vaddr)
+        .min()
+        .unwrap_or(0);
+    mapped_base.wrapping_sub(first_vaddr & !0xfff)
+}
+
+/// Contents of a file the target has mapped. Going through /proc/<pid>/root
+/// picks the file up from the target's mount namespace, which may not be ours
+pub fn read_module_file(pid: i32, path: &str) -> Option<Vec<u8>> {
+    let path = path.trim_end_matches(" (deleted)");
+    let in_target_ns = format!("/proc/{}/root{}", pid, path);
+    fs::read(&in_target_ns).or_else(|_| fs::read(path)).ok()
+}
+
+/// Address of `symbol` exported by `module_name` in `pid`, computed from the module
+/// file backing the target's mapping rather than from the injector's own copy
+pub fn resolve_remote_symbol(pid: i32, module_name: &str, symbol: &str) -> Option<u64> {
+    resolve_remote(pid, module_name, symbol, |elf, bytes| find_dynamic_symbol(elf, bytes, symbol))
+}
+
+/// Like `resolve_remote_symbol`, for a symbol that isn't exported (see `find_internal_symbol`)
+pub fn resolve_remote_internal_symbol(pid: i32, module_name: &str, symbol: &str) -> Option<u64> {
+    resolve_remote(pid, module_name, symbol, |elf, _| find_internal_symbol(elf, symbol))
+}
+
+/// A symbol exported by a module in the target
+#[derive(Debug, Clone, Copy)]
+pub struct Export {
+    pub addr: u64,
+    /// STT_GNU_IFUNC: `addr` is the resolver, which returns the actual implementation
+    pub ifunc: bool,
+}
+
+/// Every usable symbol `module_name` exports in `pid`, by name. The module is parsed
+/// once, for callers with many symbols to look up
+pub fn remote_exports(pid: i32, module_name: &str) -> Option<HashMap<String, Export>> {
+    with_remote_module(pid, module_name, |elf, _, base, _| {
+        let bias = load_bias(elf, base);
+        let exports = elf
+            .dynsyms
+            .iter()
+            .enumerate()
+            .filter(|(idx, sym)| is_usable(elf, *idx, sym))
+            .filter_map(|(_, sym)| {
+                let name = elf.dynstrtab.get_at(sym.st_name)?;
+                let export = Export { addr: bias.wrapping_add(sym.st_value), ifunc: sym.st_type() == STT_GNU_IFUNC };
+                Some((name.to_string(), export))
+            })
+            .collect();
+        Some(exports)
+    })
+}
+
+// Parse the file backing `module_name` in `pid` and hand it to `f` along with its load
+// address and path
+fn with_remote_module<T>(pid: i32, module_name: &str, f: impl FnOnce(&Elf, &[u8], u64, &str) -> Option<T>) -> Option<T> {
+    let (base, path) = get_module_mapping(pid, module_name)?;
+
+    let bytes = match read_module_file(pid, &path) {
+        Some(b) => b,
+        None => {
+            vlog!("[elf] unable to read {} for pid={}", path, pid);
+            return None;
+        }
+    };
+    let elf = match Elf::parse(&bytes) {
+        Ok(e) => e,
+        Err(e) => {
+            vlog!("[elf] failed to parse {}: {}", path, e);
+            return None;
+        }
+    };
+    f(&elf, &bytes, base, &path)
+}
+
+fn resolve_remote(pid: i32, module_name: &str, symbol: &str, find: impl Fn(&Elf, &[u8]) -> Option<Sym>) -> Option<u64> {
+    with_remote_module(pid, module_name, |elf, bytes, base, path| {
+        let sym = find(elf, bytes)?;
+        let addr = load_bias(elf, base).wrapping_add(sym.st_value);
+
+        vlog!(
+            "[elf] {}!{} st_value=0x{:x} base=0x{:x} => 0x{:x}",
+            path,
+            symbol,
+            sym.st_value,
+            base,
+            addr
+        );
+        Some(addr)
+    })
+}
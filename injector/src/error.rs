@@ -0,0 +1,153 @@
+use std::ffi::NulError;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use libc::pid_t;
+use nix::sys::signal::Signal;
+
+pub type Result<T> = std::result::Result<T, InjectError>;
+
+/// Every failure class the injector can hit, each one maps to its own exit code
+#[derive(Debug)]
+pub enum InjectError {
+    /// No running process matched the given name
+    ProcessNotFound(String),
+    /// ptrace refused to attach (permissions, SELinux, yama, already traced)
+    AttachDenied { pid: pid_t, source: io::Error },
+    /// The tracee exited or got killed while we were working on it
+    ProcessExited { pid: pid_t, code: Option<i32>, signal: Option<Signal> },
+    /// A function could not be located inside the target
+    SymbolNotResolved { module: String, symbol: String },
+    /// The remote function faulted instead of coming back through our return trap
+    RemoteCallCrashed(Box<CrashReport>),
+    /// The remote function did not return within the call timeout
+    RemoteCallTimedOut { pc: u64, timeout: Duration },
+    /// Remote mmap handed back MAP_FAILED or NULL
+    RemoteAllocFailed { ret: u64 },
+    /// Reading or writing the target's memory failed on every access method
+    MemoryAccess { addr: u64, len: usize, source: io::Error },
+    /// A remote libc function reported failure, `ret` is what it returned
+    RemoteCallFailed { function: String, ret: i64 },
+    /// Remote dlopen returned NULL
+    DlopenFailed { dlerror: Option<String> },
+    /// The library could not be unloaded from the target
+    EjectFailed { library: String, reason: String },
+    /// Target binary is built for another architecture than the injector
+    ArchMismatch { injector: String, target: String },
+    /// Bad user input, e.g. a library path with an interior NUL
+    InvalidArgument(String),
+    /// Any other OS level failure (ptrace requests, /proc access, ...)
+    Io(io::Error),
+}
+
+impl InjectError {
+    /// Process exit code for the CLI, 1 is kept for usage errors
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            InjectError::ProcessNotFound(_) => 2,
+            InjectError::AttachDenied { .. } => 3,
+            InjectError::ProcessExited { .. } => 4,
+            InjectError::SymbolNotResolved { .. } => 5,
+            InjectError::RemoteCallCrashed(_) => 6,
+            InjectError::RemoteAllocFailed { .. } => 7,
+            InjectError::DlopenFailed { .. } => 8,
+            InjectError::ArchMismatch { .. } => 9,
+            InjectError::InvalidArgument(_) => 10,
+            InjectError::Io(_) => 11,
+            InjectError::EjectFailed { .. } => 12,
+            InjectError::MemoryAccess { .. } => 13,
+            InjectError::RemoteCallTimedOut { .. } => 14,
+            InjectError::RemoteCallFailed { .. } => 15,
+        }
+    }
+}
+
+impl fmt::Display for InjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InjectError::ProcessNotFound(name) => write!(f, "process not found: {}", name),
+            InjectError::AttachDenied { pid, source } => write!(f, "ptrace attach to {} denied: {}", pid, source),
+            InjectError::ProcessExited { pid, code: Some(code), .. } => write!(f, "process {} exited with code {}", pid, code),
+            InjectError::ProcessExited { pid, signal: Some(sig), .. } => write!(f, "process {} was killed by {:?}", pid, sig),
+            InjectError::ProcessExited { pid, .. } => write!(f, "process {} is gone", pid),
+            InjectError::SymbolNotResolved { module, symbol } => write!(f, "failed to resolve {} in {}", symbol, module),
+            InjectError::RemoteCallCrashed(report) => write!(f, "remote call crashed with {:?} at pc 0x{:x}", report.signal, report.pc),
+            InjectError::RemoteCallTimedOut { pc, timeout } => write!(f, "remote call did not return within {:?}, interrupted at pc 0x{:x}", timeout, pc),
+            InjectError::RemoteAllocFailed { ret } => write!(f, "remote mmap failed: 0x{:x}", ret),
+            InjectError::MemoryAccess { addr, len, source } => write!(f, "remote memory access at 0x{:x} ({} bytes) failed: {}", addr, len, source),
+            InjectError::RemoteCallFailed { function, ret } => write!(f, "remote {} failed, returned {}", function, ret),
+            InjectError::DlopenFailed { dlerror: Some(msg) } => write!(f, "remote dlopen failed: {}", msg),
+            InjectError::DlopenFailed { dlerror: None } => write!(f, "remote dlopen returned NULL"),
+            InjectError::EjectFailed { library, reason } => write!(f, "failed to eject {}: {}", library, reason),
+            InjectError::ArchMismatch { injector, target } => write!(f, "architecture mismatch: injector is {}, target is {}", injector, target),
+            InjectError::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            InjectError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// What the target looked like when a remote call crashed
+#[derive(Debug, Clone)]
+pub struct CrashReport {
+    pub signal: Signal,
+    pub pc: u64,
+    /// Register name and value, in the order the architecture lists them
+    pub registers: Vec<(&'static str, u64)>,
+    /// Bytes around pc and the address they start at, empty if pc isn't readable
+    pub code_addr: u64,
+    pub code: Vec<u8>,
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:?} at pc 0x{:x}", self.signal, self.pc)?;
+        for row in self.registers.chunks(4) {
+            for (name, value) in row {
+                write!(f, "  {:>8} 0x{:016x}", name, value)?;
+            }
+            writeln!(f)?;
+        }
+
+        if self.code.is_empty() {
+            return write!(f, "  code at pc is not readable");
+        }
+        write!(f, "  code at 0x{:x}:", self.code_addr)?;
+        for (i, row) in self.code.chunks(16).enumerate() {
+            write!(f, "\n    0x{:x}:", self.code_addr + (i * 16) as u64)?;
+            for b in row {
+                write!(f, " {:02x}", b)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InjectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InjectError::AttachDenied { source, .. } => Some(source),
+            InjectError::MemoryAccess { source, .. } => Some(source),
+            InjectError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for InjectError {
+    fn from(e: io::Error) -> Self {
+        InjectError::Io(e)
+    }
+}
+
+impl From<nix::Error> for InjectError {
+    fn from(e: nix::Error) -> Self {
+        InjectError::Io(io::Error::from(e))
+    }
+}
+
+impl From<NulError> for InjectError {
+    fn from(e: NulError) -> Self {
+        InjectError::InvalidArgument(e.to_string())
+    }
+}
@@ -1,34 +1,76 @@
 use std::ffi::CString;
 use std::fs;
 use std::path::{Path, PathBuf};
-use libc::{pid_t, mmap, munmap, RTLD_NOW, RTLD_LOCAL, PROT_READ, PROT_WRITE, MAP_PRIVATE, MAP_ANONYMOUS};
-
-#[cfg(target_arch = "aarch64")]
-use libc::PROT_EXEC;
-
-use crate::ptrace::{ptrace_attach, ptrace_detach, ptrace_write};
-#[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
-use crate::ptrace::call_remote_function;
-use crate::utils::get_remote_function_addr;
+use libc::{pid_t, RTLD_NOW, RTLD_LOCAL, RTLD_NOLOAD, PROT_READ, PROT_WRITE, MAP_PRIVATE, MAP_ANONYMOUS};
+
+use crate::error::{InjectError, Result};
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+use crate::session::{CallArg, Injector, Session};
+use crate::utils::{
+    find_code_mapping, get_auxv_value, get_process_arch, get_process_bitness, is_module_mapped, AT_ENTRY, AT_HWCAP,
+};
+
+/// glibc's Debian multiarch library directory and dynamic linker for a target of the
+/// given word size. A 32-bit target of a 64-bit injector runs the compat architecture
+fn glibc_layout(bits: u32) -> (&'static str, &'static str) {
+    if cfg!(any(target_arch = "aarch64", target_arch = "arm")) {
+        if bits == 64 {
+            ("/lib/aarch64-linux-gnu", "/lib/ld-linux-aarch64.so.1")
+        } else {
+            ("/lib/arm-linux-gnueabihf", "/lib/ld-linux-armhf.so.3")
+        }
+    } else if bits == 64 {
+        ("/lib/x86_64-linux-gnu", "/lib64/ld-linux-x86-64.so.2")
+    } else {
+        ("/lib/i386-linux-gnu", "/lib/ld-linux.so.2")
+    }
+}
 
-/// Choose a reasonable default path for libc based on target pointer width
-fn default_libc_path() -> &'static str {
-    if cfg!(target_pointer_width = "64") {
-        "/system/lib64/libc.so"
+/// Choose a reasonable default path for libc based on the target's word size
+fn default_libc_path(bits: u32) -> String {
+    if cfg!(not(target_os = "android")) {
+        format!("{}/libc.so.6", glibc_layout(bits).0)
+    } else if bits == 64 {
+        "/system/lib64/libc.so".to_string()
     } else {
-        "/system/lib/libc.so"
+        "/system/lib/libc.so".to_string()
     }
 }
 
 /// Choose a reasonable default path for the dynamic linker
-fn default_linker_path() -> &'static str {
-    if cfg!(target_pointer_width = "64") {
+fn default_linker_path(bits: u32) -> &'static str {
+    if cfg!(not(target_os = "android")) {
+        glibc_layout(bits).1
+    } else if bits == 64 {
         "/system/bin/linker64"
     } else {
         "/system/bin/linker"
     }
 }
 
+/// Modules that may export `dlopen` in the session's target, in the order they should be tried
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn dlopen_module_candidates(session: &Session) -> Vec<String> {
+    let bits = session.bits();
+    if cfg!(not(target_os = "android")) {
+        // glibc >= 2.34 carries dlopen in libc itself, older ones in libdl
+        let libdl = format!("{}/libdl.so.2", glibc_layout(bits).0);
+        vec![session.libc_path().to_string(), resolve_if_symlink(&libdl)]
+    } else if bits == 64 {
+        vec![
+            session.linker_path().to_string(),
+            "/apex/com.android.runtime/lib64/bionic/libdl.so".to_string(),
+            "/system/lib64/libdl.so".to_string(),
+        ]
+    } else {
+        vec![
+            session.linker_path().to_string(),
+            "/apex/com.android.runtime/lib/bionic/libdl.so".to_string(),
+            "/system/lib/libdl.so".to_string(),
+        ]
+    }
+}
+
 /// Resolve a path if it is a symlink, returning an absolute, canonical path
 /// Falls back gracefully to the original if resolution fails
 fn resolve_if_symlink(path: &str) -> String {
@@ -62,160 +104,512 @@ fn resolve_if_symlink(path: &str) -> String {
     }
 }
 
-/// Public helpers: return the *resolved* (non-symlink) full paths
+/// Public helpers: return the *resolved* (non-symlink) full paths for the injector's own word size
 pub fn get_libc_path() -> String {
-    resolve_if_symlink(default_libc_path())
+    get_libc_path_for(usize::BITS)
 }
 
 pub fn get_linker_path() -> String {
-    resolve_if_symlink(default_linker_path())
+    get_linker_path_for(usize::BITS)
 }
 
-#[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
-pub fn inject_library(pid: pid_t, library_path: &str) -> Result<u64, Box<dyn std::error::Error>> {
-    ptrace_attach(pid)?;
+/// libc of a 32 or 64-bit target, which differs from ours when driving a compat process
+pub fn get_libc_path_for(bits: u32) -> String {
+    resolve_if_symlink(&default_libc_path(bits))
+}
 
-    let handle = call_dlopen(pid, library_path)?;
+pub fn get_linker_path_for(bits: u32) -> String {
+    resolve_if_symlink(default_linker_path(bits))
+}
 
-    #[cfg(debug_assertions)]
-    {
-        if handle == 0 {
-            println!("Injection failed...");
-        } else {
-            println!("Injection ended successfully...");
-        }
+/// Refuse targets whose remote call ABI we can't drive. Same architecture is fine,
+/// as is a 32-bit ARM process under an aarch64 injector; returns whether the target
+/// is such a 32-bit (compat) process. If the executable can't be read we fall back
+/// to the word size auxv reports and let the injection fail later if needed
+pub(crate) fn check_target_arch(pid: pid_t) -> Result<bool> {
+    let injector = std::env::consts::ARCH;
+    let target = match get_process_arch(pid) {
+        Some(target) => target,
+        None => return Ok(injector == "aarch64" && get_process_bitness(pid) == Some(32)),
+    };
+
+    match (injector, target.as_str()) {
+        (i, t) if i == t => Ok(false),
+        ("aarch64", "arm") => Ok(true),
+        _ => Err(InjectError::ArchMismatch { injector: injector.to_string(), target }),
     }
+}
 
-    ptrace_detach(pid)?;
-    Ok(handle)
+/// mmap reports failure as MAP_FAILED, which is -1 in the tracee's own word size
+fn is_map_failed(ret: u64) -> bool {
+    ret == 0 || ret == u64::MAX || ret == u32::MAX as u64
 }
 
-#[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
-fn call_mmap(pid: pid_t, length: usize) -> Result<u64, Box<dyn std::error::Error>> {
-    #[cfg(target_arch = "aarch64")]
-    {
-        println!("[mmap] Calling mmap via library function");
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub fn inject_library(pid: pid_t, library_path: &str) -> Result<u64> {
+    Injector::new(pid).inject(library_path)
+}
 
-        let local = mmap as usize as u64;
-        let remote = get_remote_function_addr(pid, get_libc_path().as_str(), local).ok_or("Failed to resolve mmap")?;
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub(crate) fn call_mmap(session: &mut Session, length: usize, prot: i32) -> Result<u64> {
+    let remote = session.resolve(session.libc_path(), "mmap")?;
 
-        let args = [
-            0,
-            length as u64,
-            (PROT_READ | PROT_WRITE | PROT_EXEC) as u64,
-            (MAP_PRIVATE | MAP_ANONYMOUS) as u64,
-            !0u64, // fd (-1)
-            0,
-        ];
+    let args = [
+        0,
+        length as u64,
+        prot as u64,
+        (MAP_PRIVATE | MAP_ANONYMOUS) as u64,
+        !0u64, // fd (-1)
+        0,
+    ];
+
+    #[cfg(debug_assertions)]
+    eprintln!("[mmap] Calling mmap at 0x{:x} with args: {:?}", remote, args);
+    let result = session.call(remote, &args)?;
+    #[cfg(debug_assertions)]
+    eprintln!("[mmap] mmap returned: 0x{:x}", result);
 
-        println!("[mmap] Calling mmap at 0x{:x} with args: {:?}", remote, args);
-        Ok(call_remote_function(pid, remote, &args, remote)?)
+    if is_map_failed(result) {
+        return Err(InjectError::RemoteAllocFailed { ret: result });
     }
+    session.allocated(result, length);
+    Ok(result)
+}
 
-    #[cfg(target_arch = "arm")]
-    {
-        let local = mmap as usize as u64;
-        let remote = get_remote_function_addr(pid, get_libc_path().as_str(), local).ok_or("Failed to resolve mmap")?;
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub(crate) fn call_munmap(session: &mut Session, addr: u64, length: usize) -> Result<u64> {
+    let remote = session.resolve(session.libc_path(), "munmap")?;
 
-        let args = [
-            0,
-            length as u64,
-            (PROT_READ | PROT_WRITE) as u64,
-            (MAP_PRIVATE | MAP_ANONYMOUS) as u64,
-            0,
-            0,
-        ];
+    let args = [addr, length as u64];
 
-        #[cfg(debug_assertions)]
-        println!("mmap: call at 0x{:x} with size {}", remote, length);
+    #[cfg(debug_assertions)]
+    eprintln!("[munmap] Calling munmap at 0x{:x} with args: {:?}", remote, args);
+    let result = session.call(remote, &args)?;
+    session.freed(addr);
+    Ok(result)
+}
 
-        let result = call_remote_function(pid, remote, &args, 0)?;
-        println!("[mmap] mmap returned: 0x{:x}", result);
-        Ok(result)
+/// Copy `bytes` into memory mapped in the target for as long as `f` runs, which gets its
+/// address. The mapping is gone again however `f` ends, after a crashed or timed out
+/// call the rollback has unmapped it already
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub(crate) fn with_remote_bytes<T>(
+    session: &mut Session,
+    bytes: &[u8],
+    f: impl FnOnce(&mut Session, u64) -> Result<T>,
+) -> Result<T> {
+    // mmap refuses a length of 0
+    let len = bytes.len().max(1);
+    let addr = call_mmap(session, len, PROT_READ | PROT_WRITE)?;
+    let result = session.memory().write(addr, bytes).and_then(|_| f(session, addr));
+    if !session.is_allocated(addr) {
+        return result;
     }
+    let freed = call_munmap(session, addr, len);
+    let value = result?;
+    freed.map(|_| value)
 }
 
-#[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
-fn call_munmap(pid: pid_t, addr: u64, length: usize) -> Result<u64, Box<dyn std::error::Error>> {
-    #[cfg(target_arch = "aarch64")]
-    {
-        // Use library function for aarch64 (same approach as 32-bit ARM)
-        println!("[munmap] Calling munmap via library function");
+/// First module from `dlopen_module_candidates` that exports `symbol` in the target
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub(crate) fn resolve_dl_function(session: &Session, symbol: &str) -> Result<u64> {
+    let candidates = dlopen_module_candidates(session);
+    candidates
+        .iter()
+        .find_map(|module| {
+            #[cfg(debug_assertions)]
+            eprintln!("[{}] Trying module: {}", symbol, module);
+            session.resolve(module, symbol).ok()
+        })
+        .ok_or_else(|| InjectError::SymbolNotResolved {
+            module: candidates.join(", "),
+            symbol: symbol.to_string(),
+        })
+}
 
-        let local = munmap as usize as u64;
-        let remote = get_remote_function_addr(pid, get_libc_path().as_str(), local).ok_or("Failed to resolve munmap")?;
+// Android 7's linker has no __loader_* entry points, only its own dlopen_ext in .symtab,
+// prefixed with __dl_ like all its symbols. It takes the loader lock around do_dlopen,
+// which is never called directly: without the lock it races the target's own threads
+// loading or unloading and can corrupt the linker's list of libraries
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+const ANDROID_N_DLOPEN_EXT: &str = "__dl__ZL10dlopen_extPKciPK17android_dlextinfoPv";
+
+/// A way into the target's loader, `resolve_dlopen` picks the best one available
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+#[derive(Debug, Clone, Copy)]
+enum DlopenEntry {
+    /// Android 8+: the linker's `__loader_dlopen(path, flags, caller)` or
+    /// `__loader_android_dlopen_ext(path, flags, extinfo, caller)`, what libdl's wrappers call
+    Loader(u64),
+    /// Android 7: the linker's internal `dlopen_ext(path, flags, extinfo, caller)`
+    Internal(u64),
+    /// `dlopen(path, flags)` or `android_dlopen_ext(path, flags, extinfo)`. On Android 7+
+    /// the caller is taken from the return address, which is our trap, so the library may
+    /// end up in the wrong linker namespace
+    Plain(u64),
+}
 
-        let args = [addr, length as u64];
+/// Pick the entry point for a dlopen, with an `android_dlextinfo` (`ext`) or without
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn resolve_dlopen(session: &Session, ext: bool) -> Result<DlopenEntry> {
+    if cfg!(target_os = "android") {
+        let linker = session.linker_path();
+        let loader = if ext { "__loader_android_dlopen_ext" } else { "__loader_dlopen" };
+        // Android 8 and 9 hand them to libdl without exporting them, .symtab has them
+        // with the __dl_ prefix
+        let addr = session
+            .resolve(linker, loader)
+            .or_else(|_| session.resolve_internal(linker, &format!("__dl_{}", loader)));
+        if let Ok(addr) = addr {
+            return Ok(DlopenEntry::Loader(addr));
+        }
+        if let Ok(addr) = session.resolve_internal(linker, ANDROID_N_DLOPEN_EXT) {
+            return Ok(DlopenEntry::Internal(addr));
+        }
+        #[cfg(debug_assertions)]
+        eprintln!("[dlopen] no caller-aware entry point in {}, using the plain one", linker);
+    }
+    resolve_dl_function(session, if ext { "android_dlopen_ext" } else { "dlopen" }).map(DlopenEntry::Plain)
+}
 
-        println!("[munmap] Calling munmap at 0x{:x} with args: {:?}", remote, args);
-        Ok(call_remote_function(pid, remote, &args, remote)?)
+/// An address inside the target's own code for the loader to attribute a dlopen to, the
+/// library is loaded into that module's linker namespace. An app's native library puts
+/// it in the app's classloader namespace, otherwise the main executable (default
+/// namespace) is used
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn dlopen_caller(session: &Session) -> u64 {
+    let app_library = find_code_mapping(session.pid(), |path| path.starts_with("/data/app/") && path.ends_with(".so"));
+    if let Some((addr, _path)) = app_library {
+        #[cfg(debug_assertions)]
+        eprintln!("[dlopen] loading on behalf of {}", _path);
+        return addr;
     }
+    get_auxv_value(session.pid(), session.bits(), AT_ENTRY)
+        .or_else(|| find_code_mapping(session.pid(), |path| path == session.libc_path()).map(|(addr, _)| addr))
+        .unwrap_or(0)
+}
 
-    #[cfg(target_arch = "arm")]
-    {
-        // Use library function for 32-bit ARM
-        let local = munmap as usize as u64;
-        let remote = get_remote_function_addr(pid, get_libc_path().as_str(), local).ok_or("Failed to resolve munmap")?;
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub(crate) fn call_dlopen(session: &mut Session, lib_path: &str) -> Result<u64> {
+    call_dlopen_flags(session, lib_path, RTLD_NOW | RTLD_LOCAL)
+}
 
-        let args = [addr, length as u64];
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn call_dlopen_flags(session: &mut Session, lib_path: &str, flags: i32) -> Result<u64> {
+    let c_path = CString::new(lib_path)?;
+    let mmap_addr = call_mmap(session, 0x400, PROT_READ | PROT_WRITE)?;
 
-        #[cfg(debug_assertions)]
-        println!("munmap: call at 0x{:x} addr=0x{:x}, size={}", remote, addr, length);
+    session.memory().write(mmap_addr, c_path.as_bytes_with_nul())?;
 
-        Ok(call_remote_function(pid, remote, &args, 0)?)
+    #[cfg(debug_assertions)]
+    eprintln!("dlopen: path='{}'", lib_path);
+
+    let result = remote_dlopen(session, mmap_addr, flags, None)?;
+
+    // dlerror state is per thread, grab it before anything else runs on this one
+    let dlerror = if result == 0 { call_dlerror(session) } else { None };
+
+    call_munmap(session, mmap_addr, 0x400)?;
+    if result == 0 {
+        return Err(InjectError::DlopenFailed { dlerror });
     }
+    Ok(result)
 }
 
-#[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
-fn call_dlopen(pid: pid_t, lib_path: &str) -> Result<u64, Box<dyn std::error::Error>> {
-    let local = libc::dlopen as usize as u64;
+/// dlopen the path at `path_addr` in the target through the best entry point there is,
+/// `extinfo` is the address of an `android_dlextinfo`. Returns the raw handle, 0 on failure
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub(crate) fn remote_dlopen(session: &mut Session, path_addr: u64, flags: i32, extinfo: Option<u64>) -> Result<u64> {
+    let entry = resolve_dlopen(session, extinfo.is_some())?;
+    let flags = flags as u64;
+    let (remote, args) = match (entry, extinfo) {
+        (DlopenEntry::Loader(addr), None) => (addr, vec![path_addr, flags, dlopen_caller(session)]),
+        (DlopenEntry::Loader(addr), Some(ext)) | (DlopenEntry::Internal(addr), Some(ext)) => {
+            (addr, vec![path_addr, flags, ext, dlopen_caller(session)])
+        }
+        (DlopenEntry::Internal(addr), None) => (addr, vec![path_addr, flags, 0, dlopen_caller(session)]),
+        (DlopenEntry::Plain(addr), None) => (addr, vec![path_addr, flags]),
+        (DlopenEntry::Plain(addr), Some(ext)) => (addr, vec![path_addr, flags, ext]),
+    };
 
-    // First try to find dlopen in the linker
-    let linker_path = get_linker_path();
-    println!("[dlopen] Trying linker first: {}", linker_path);
+    #[cfg(debug_assertions)]
+    eprintln!("[dlopen] calling {:x?} with args: {:x?}", entry, args);
+    let result = session.call(remote, &args)?;
+    #[cfg(debug_assertions)]
+    eprintln!("[dlopen] dlopen returned: 0x{:x}", result);
+    Ok(result)
+}
 
-    let remote = get_remote_function_addr(pid as i32, &linker_path, local)
-        .or_else(|| {
-            // If not found in linker, try libdl files
-            println!("[dlopen] Not found in linker, trying libdl files");
-            let possible_libs = if cfg!(target_pointer_width = "64") {
-                vec![
-                    "/apex/com.android.runtime/lib64/bionic/libdl.so",
-                    "/system/lib64/libdl.so",
-                ]
-            } else {
-                vec![
-                    "/apex/com.android.runtime/lib/bionic/libdl.so",
-                    "/system/lib/libdl.so",
-                ]
-            };
+// android_dlextinfo.flags bit: load from library_fd instead of opening the path
+const ANDROID_DLEXT_USE_LIBRARY_FD: u64 = 0x10;
+const MFD_CLOEXEC: u64 = 1;
+
+/// memfd_create's syscall number for the target, called through libc's syscall()
+/// since bionic only has a wrapper from Android 11 on
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn memfd_create_nr(session: &Session) -> u64 {
+    match (std::env::consts::ARCH, session.bits()) {
+        ("aarch64", 64) => 279,
+        ("aarch64", _) | ("arm", _) => 385,
+        ("x86_64", _) => 319,
+        _ => 356,
+    }
+}
 
-            possible_libs.iter()
-                .find_map(|lib_path| {
-                    println!("[dlopen] Trying libdl: {}", lib_path);
-                    get_remote_function_addr(pid as i32, lib_path, local)
-                })
-        })
-        .ok_or("Failed to resolve dlopen in linker or libdl")?;
+/// `struct android_dlextinfo` asking to load from `fd`, laid out for the target's word
+/// size: flags, reserved_addr, reserved_size, relro_fd, library_fd, library_fd_offset,
+/// library_namespace
+fn android_dlextinfo(fd: i32, bits: u32) -> Vec<u8> {
+    let mut info = ANDROID_DLEXT_USE_LIBRARY_FD.to_le_bytes().to_vec();
+    let word = if bits == 64 { 8 } else { 4 };
+    info.resize(8 + 2 * word, 0);
+    info.extend_from_slice(&(-1i32).to_le_bytes());
+    info.extend_from_slice(&fd.to_le_bytes());
+    // library_fd_offset and library_namespace stay 0
+    info.resize(if bits == 64 { 48 } else { 40 }, 0);
+    info
+}
 
-    // Get mmap address for the new call_remote_function signature
-    let local_mmap = mmap as usize as u64;
-    let mmap_remote = get_remote_function_addr(pid as i32, get_libc_path().as_str(), local_mmap)
-        .ok_or("Failed to resolve mmap for dlopen")?;
+/// Load `lib_path` without the target ever opening it: the file is read here, copied
+/// into a memfd created inside the target and dlopen'ed from there. The target only
+/// needs to be allowed to map the memfd
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub(crate) fn call_dlopen_memfd(session: &mut Session, lib_path: &str) -> Result<u64> {
+    let contents = fs::read(lib_path)?;
+    // shows up as /memfd:<name> in the target's maps
+    let name = Path::new(lib_path).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let c_name = CString::new(name)?;
+
+    let scratch = call_mmap(session, 0x400, PROT_READ | PROT_WRITE)?;
+    session.memory().write(scratch, c_name.as_bytes_with_nul())?;
+
+    let syscall = session.resolve(session.libc_path(), "syscall")?;
+    let fd = session.call(syscall, &[memfd_create_nr(session), scratch, MFD_CLOEXEC])? as i32;
+    #[cfg(debug_assertions)]
+    eprintln!("[memfd] memfd_create returned: {}", fd);
+    if fd < 0 {
+        call_munmap(session, scratch, 0x400)?;
+        return Err(InjectError::RemoteCallFailed { function: "memfd_create".to_string(), ret: fd as i64 });
+    }
 
-    let mmap_addr = call_mmap(pid, 0x400)?;
-    let c_path = CString::new(lib_path)?;
+    let result = write_memfd(session, fd, &contents).and_then(|_| dlopen_fd(session, fd, scratch));
+    let dlerror = match result {
+        Ok(0) => call_dlerror(session),
+        _ => None,
+    };
 
-    ptrace_write(pid, mmap_addr as *mut u8, c_path.as_bytes_with_nul())?;
+    // the loader has its own mappings of the memfd by now, our fd is no longer needed
+    let close = session.resolve(session.libc_path(), "close")?;
+    session.call(close, &[fd as u64])?;
+    call_munmap(session, scratch, 0x400)?;
 
-    let args = [mmap_addr, (RTLD_NOW | RTLD_LOCAL) as u64];
+    match result? {
+        0 => Err(InjectError::DlopenFailed { dlerror }),
+        handle => Ok(handle),
+    }
+}
 
+/// Copy `contents` into the target's `fd` through a buffer mapped in the target
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn write_memfd(session: &mut Session, fd: i32, contents: &[u8]) -> Result<()> {
+    let write = session.resolve(session.libc_path(), "write")?;
+    let buffer = call_mmap(session, contents.len(), PROT_READ | PROT_WRITE)?;
+    session.memory().write(buffer, contents)?;
+
+    let mut written = 0;
+    let result = loop {
+        if written == contents.len() {
+            break Ok(());
+        }
+        let args = [fd as u64, buffer + written as u64, (contents.len() - written) as u64];
+        let ret = match session.call(write, &args) {
+            Ok(ret) => ret as i32,
+            Err(e) => break Err(e),
+        };
+        if ret <= 0 {
+            break Err(InjectError::RemoteCallFailed { function: "write".to_string(), ret: ret as i64 });
+        }
+        written += ret as usize;
+    };
     #[cfg(debug_assertions)]
-    println!("dlopen: remote=0x{:x}, path='{}'", remote, lib_path);
+    eprintln!("[memfd] wrote {} of {} bytes", written, contents.len());
+
+    call_munmap(session, buffer, contents.len())?;
+    result
+}
 
-    let result = call_remote_function(pid, remote, &args, mmap_remote)?;
-    println!("[dlopen] dlopen returned: 0x{:x}", result);
+/// dlopen the library in the target's `fd`. The name, already at `scratch`, is what the
+/// library goes by on Android, plain Linux loads it through /proc/self/fd
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn dlopen_fd(session: &mut Session, fd: i32, scratch: u64) -> Result<u64> {
+    let flags = RTLD_NOW | RTLD_LOCAL;
+    if cfg!(target_os = "android") {
+        let extinfo = scratch + 0x200;
+        session.memory().write(extinfo, &android_dlextinfo(fd, session.bits()))?;
+        remote_dlopen(session, scratch, flags, Some(extinfo))
+    } else {
+        let path = CString::new(format!("/proc/self/fd/{}", fd))?;
+        let path_addr = scratch + 0x200;
+        session.memory().write(path_addr, path.as_bytes_with_nul())?;
+        remote_dlopen(session, path_addr, flags, None)
+    }
+}
 
-    call_munmap(pid, mmap_addr, 0x400)?;
+/// Fetch the target's dlerror() text. Failing to get it must not hide the
+/// original error, so anything going wrong here just yields None
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn call_dlerror(session: &mut Session) -> Option<String> {
+    let remote = match resolve_dl_function(session, "dlerror") {
+        Ok(addr) => addr,
+        Err(_e) => {
+            #[cfg(debug_assertions)]
+            eprintln!("[dlerror] {}", _e);
+            return None;
+        }
+    };
+
+    let msg_addr = match session.call(remote, &[]) {
+        Ok(addr) => addr,
+        Err(_e) => {
+            #[cfg(debug_assertions)]
+            eprintln!("[dlerror] remote call failed: {}", _e);
+            return None;
+        }
+    };
+    if msg_addr == 0 {
+        return None;
+    }
+
+    let msg = session.memory().read_cstring(msg_addr).ok()?;
+    #[cfg(debug_assertions)]
+    eprintln!("[dlerror] {}", msg);
+    Some(msg)
+}
+
+/// Remote dlsym on a handle from dlopen
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub(crate) fn call_dlsym(session: &mut Session, handle: u64, symbol: &str) -> Result<u64> {
+    let remote = resolve_dl_function(session, "dlsym")?;
+
+    let c_symbol = CString::new(symbol)?;
+    let result = with_remote_bytes(session, c_symbol.as_bytes_with_nul(), |session, addr| {
+        session.call(remote, &[handle, addr])
+    })?;
+    #[cfg(debug_assertions)]
+    eprintln!("[dlsym] {} => 0x{:x}", symbol, result);
+
+    if result == 0 {
+        return Err(InjectError::SymbolNotResolved { module: format!("handle 0x{:x}", handle), symbol: symbol.to_string() });
+    }
     Ok(result)
 }
+
+/// Call a library's entry point as `int entry(const void *payload, size_t len)` with a copy
+/// of `payload` in the target, returning its status
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub(crate) fn call_entry(session: &mut Session, entry: u64, payload: &[u8]) -> Result<i32> {
+    let status = with_remote_bytes(session, payload, |session, addr| {
+        session.call(entry, &[addr, payload.len() as u64])
+    })? as i32;
+    #[cfg(debug_assertions)]
+    eprintln!("[entry] 0x{:x} returned {}", entry, status);
+    Ok(status)
+}
+
+/// Call the resolver of a GNU IFUNC symbol for the implementation it picks. Resolvers
+/// get the hwcaps as their first argument on arm, the others ignore it
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub(crate) fn call_ifunc_resolver(session: &mut Session, resolver: u64) -> Result<u64> {
+    let hwcap = get_auxv_value(session.pid(), session.bits(), AT_HWCAP).unwrap_or(0);
+    let addr = session.call(resolver, &[hwcap])?;
+    vlog!("[ifunc] resolver 0x{:x} picked 0x{:x}", resolver, addr);
+    Ok(addr)
+}
+
+/// Address of the calling thread's errno in the target, glibc and bionic name the
+/// function differently
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub(crate) fn call_errno_location(session: &mut Session) -> Result<u64> {
+    let libc = session.libc_path().to_string();
+    let remote = session.resolve(&libc, "__errno_location").or_else(|_| session.resolve(&libc, "__errno"))?;
+    session.call(remote, &[])
+}
+
+/// `Session::call` with string arguments as well, see `CallArg`
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub(crate) fn call_with_args(session: &mut Session, func_addr: u64, args: &[CallArg]) -> Result<u64> {
+    // strings go one after the other into a single buffer, their arguments hold the
+    // offset into it until the buffer has an address
+    let mut buffer = Vec::new();
+    let mut values = Vec::with_capacity(args.len());
+    let mut strings = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        match arg {
+            CallArg::Int(value) => values.push(*value),
+            CallArg::Str(s) => {
+                values.push(buffer.len() as u64);
+                buffer.extend_from_slice(CString::new(s.as_str())?.as_bytes_with_nul());
+                strings.push(i);
+            }
+        }
+    }
+    if strings.is_empty() {
+        return session.call(func_addr, &values);
+    }
+
+    with_remote_bytes(session, &buffer, |session, base| {
+        for &i in &strings {
+            values[i] += base;
+        }
+        session.call(func_addr, &values)
+    })
+}
+
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn call_dlclose(session: &mut Session, handle: u64) -> Result<()> {
+    let remote = resolve_dl_function(session, "dlclose")?;
+
+    #[cfg(debug_assertions)]
+    eprintln!("dlclose: remote=0x{:x}, handle=0x{:x}", remote, handle);
+
+    let result = session.call(remote, &[handle])?;
+    #[cfg(debug_assertions)]
+    eprintln!("[dlclose] dlclose returned: 0x{:x}", result);
+
+    if result as i32 != 0 {
+        let reason = call_dlerror(session).unwrap_or_else(|| format!("dlclose returned {}", result as i32));
+        return Err(InjectError::EjectFailed { library: format!("handle 0x{:x}", handle), reason });
+    }
+    Ok(())
+}
+
+/// Unload a library injected earlier. Without a `handle` one is looked up with
+/// RTLD_NOLOAD, which takes a reference of its own that gets dropped as well
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub(crate) fn eject_library(session: &mut Session, lib_path: &str, handle: Option<u64>) -> Result<()> {
+    let not_loaded = |reason: String| InjectError::EjectFailed { library: lib_path.to_string(), reason };
+
+    let (handle, references) = match handle {
+        Some(h) => (h, 1),
+        None => match call_dlopen_flags(session, lib_path, RTLD_NOW | RTLD_NOLOAD) {
+            Ok(h) => (h, 2),
+            Err(InjectError::DlopenFailed { .. }) => return Err(not_loaded("not loaded in the target".to_string())),
+            Err(e) => return Err(e),
+        },
+    };
+
+    for _ in 0..references {
+        call_dlclose(session, handle)?;
+    }
+
+    let mapped_path = resolve_if_symlink(lib_path);
+    if is_module_mapped(session.pid(), &mapped_path) {
+        return Err(not_loaded(
+            "still mapped after dlclose, it is either referenced elsewhere or can't be unloaded (NODELETE, TLS destructors)".to_string(),
+        ));
+    }
+
+    #[cfg(debug_assertions)]
+    eprintln!("[eject] {} unloaded", lib_path);
+    Ok(())
+}
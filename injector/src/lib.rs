@@ -0,0 +1,28 @@
+//! Remote library injection into running processes via ptrace
+//!
+//! `Injector` builds a `Session` against a single pid; the session exposes the
+//! individual steps (resolve, call, inject, detach) so they can be driven from
+//! other Rust code, the `injector` binary is a thin wrapper on top of it.
+
+#[macro_use]
+pub mod utils;
+pub mod elf;
+pub mod error;
+pub mod injector;
+pub mod memory;
+pub mod packages;
+pub mod process;
+pub mod ptrace;
+pub mod watch;
+
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+mod loader;
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+mod session;
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+mod zygote;
+
+pub use error::{CrashReport, InjectError, Result};
+pub use ptrace::AttachMode;
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+pub use session::{CallArg, Injector, Session};
@@ -0,0 +1,402 @@
+//! Manual mapping: loading a library into the target without its dynamic linker
+//!
+//! For targets where dlopen is hooked, confined by linker namespaces or missing. The
+//! library is parsed here, laid out and relocated in a local image, then copied into
+//! memory mapped in the target, protected and initialized through remote calls. The
+//! linker never hears of it: no dlsym/dlclose on it, no dl_iterate_phdr entry (so no
+//! C++ exception unwinding through it), no thread-local storage and no dependency
+//! loading, whatever it links against has to be loaded in the target already.
+
+use std::collections::HashMap;
+
+use goblin::elf::dynamic::{DT_INIT, DT_INIT_ARRAY, DT_INIT_ARRAYSZ};
+use goblin::elf::header::{EM_386, EM_AARCH64, EM_ARM, EM_X86_64, ET_DYN};
+use goblin::elf::program_header::{PF_R, PF_W, PF_X, PT_GNU_RELRO, PT_LOAD, PT_TLS};
+use goblin::elf::reloc::*;
+use goblin::elf::section_header::SHN_UNDEF;
+use goblin::elf::sym::STB_WEAK;
+use goblin::elf::Elf;
+use libc::{PROT_EXEC, PROT_READ, PROT_WRITE};
+
+use crate::elf::{find_dynamic_symbol, load_bias, remote_exports, Export};
+use crate::error::{InjectError, Result};
+use crate::injector::{call_ifunc_resolver, call_mmap, call_munmap};
+use crate::session::Session;
+
+const PAGE_MASK: u64 = 0xfff;
+
+// Relative relocations in the compact RELR format, goblin doesn't know these tags. Android
+// used its own tags before they were standardized
+const DT_RELRSZ: u64 = 35;
+const DT_RELR: u64 = 36;
+const DT_ANDROID_RELR: u64 = 0x6fff_e000;
+const DT_ANDROID_RELRSZ: u64 = 0x6fff_e001;
+// Android's packed (APS2) relocations, which we can't decode
+const DT_ANDROID_REL: u64 = 0x6000_000f;
+const DT_ANDROID_RELA: u64 = 0x6000_0011;
+
+/// What a relocation asks for, the same few kinds under each architecture's numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RelocKind {
+    None,
+    /// B + A
+    Relative,
+    /// S (+ A), a GOT or PLT slot
+    Slot,
+    /// S + A, a pointer in data
+    Absolute,
+}
+
+fn reloc_kind(machine: u16, r_type: u32) -> Option<RelocKind> {
+    let kind = match (machine, r_type) {
+        (EM_AARCH64, R_AARCH64_NONE) | (EM_ARM, R_ARM_NONE) | (EM_X86_64, R_X86_64_NONE) | (EM_386, R_386_NONE) => {
+            RelocKind::None
+        }
+        (EM_AARCH64, R_AARCH64_RELATIVE)
+        | (EM_ARM, R_ARM_RELATIVE)
+        | (EM_X86_64, R_X86_64_RELATIVE)
+        | (EM_386, R_386_RELATIVE) => RelocKind::Relative,
+        (EM_AARCH64, R_AARCH64_GLOB_DAT | R_AARCH64_JUMP_SLOT)
+        | (EM_ARM, R_ARM_GLOB_DAT | R_ARM_JUMP_SLOT)
+        | (EM_X86_64, R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT)
+        | (EM_386, R_386_GLOB_DAT | R_386_JMP_SLOT) => RelocKind::Slot,
+        (EM_AARCH64, R_AARCH64_ABS64) | (EM_ARM, R_ARM_ABS32) | (EM_X86_64, R_X86_64_64) | (EM_386, R_386_32) => {
+            RelocKind::Absolute
+        }
+        _ => return None,
+    };
+    Some(kind)
+}
+
+/// The library as it will look in the target, built locally so relocating it doesn't
+/// cost a remote write per slot
+struct Image {
+    bytes: Vec<u8>,
+    /// Lowest (page aligned) vaddr, where `bytes` starts
+    first: u64,
+    /// Added to a vaddr to get the address in the target
+    bias: u64,
+    word: usize,
+}
+
+impl Image {
+    fn offset(&self, vaddr: u64, len: usize) -> Result<usize> {
+        vaddr
+            .checked_sub(self.first)
+            .map(|offset| offset as usize)
+            .filter(|offset| offset + len <= self.bytes.len())
+            .ok_or_else(|| InjectError::InvalidArgument(format!("address 0x{:x} is outside the library's segments", vaddr)))
+    }
+
+    fn read_word(&self, vaddr: u64) -> Result<u64> {
+        let offset = self.offset(vaddr, self.word)?;
+        let mut word = [0u8; 8];
+        word[..self.word].copy_from_slice(&self.bytes[offset..offset + self.word]);
+        Ok(u64::from_le_bytes(word))
+    }
+
+    fn write_word(&mut self, vaddr: u64, value: u64) -> Result<()> {
+        let offset = self.offset(vaddr, self.word)?;
+        self.bytes[offset..offset + self.word].copy_from_slice(&value.to_le_bytes()[..self.word]);
+        Ok(())
+    }
+}
+
+/// Symbols the library imports, looked up in the modules it needs as the target has them
+struct Imports {
+    modules: Vec<(String, HashMap<String, Export>)>,
+    /// IFUNC resolvers already called
+    resolved: HashMap<String, u64>,
+}
+
+impl Imports {
+    fn new(session: &Session, needed: &[&str]) -> Self {
+        let mut names: Vec<String> = needed.iter().map(|n| n.to_string()).collect();
+        names.push(session.libc_path().to_string());
+        let modules = names
+            .into_iter()
+            .filter_map(|name| match remote_exports(session.pid(), &name) {
+                Some(exports) => Some((name, exports)),
+                None => {
+                    vlog!("[manual] {} is not loaded in the target, nothing can be imported from it", name);
+                    None
+                }
+            })
+            .collect();
+        Imports { modules, resolved: HashMap::new() }
+    }
+
+    fn lookup(&mut self, session: &mut Session, name: &str) -> Result<Option<u64>> {
+        if let Some(&addr) = self.resolved.get(name) {
+            return Ok(Some(addr));
+        }
+        let Some(export) = self.modules.iter().find_map(|(_, exports)| exports.get(name).copied()) else {
+            return Ok(None);
+        };
+        if !export.ifunc {
+            return Ok(Some(export.addr));
+        }
+
+        let addr = call_ifunc_resolver(session, export.addr)?;
+        self.resolved.insert(name.to_string(), addr);
+        Ok(Some(addr))
+    }
+
+    fn searched(&self) -> String {
+        self.modules.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// ELF machine a library needs to run in the session's target
+fn expected_machine(session: &Session) -> u16 {
+    match (std::env::consts::ARCH, session.bits()) {
+        ("aarch64", 64) => EM_AARCH64,
+        ("aarch64", _) | ("arm", _) => EM_ARM,
+        ("x86_64", _) => EM_X86_64,
+        _ => EM_386,
+    }
+}
+
+fn prot_of(p_flags: u32) -> i32 {
+    let mut prot = 0;
+    if p_flags & PF_R != 0 {
+        prot |= PROT_READ;
+    }
+    if p_flags & PF_W != 0 {
+        prot |= PROT_WRITE;
+    }
+    if p_flags & PF_X != 0 {
+        prot |= PROT_EXEC;
+    }
+    prot
+}
+
+/// Map, relocate and initialize `lib_path` in the target, returning its load address
+pub(crate) fn map_library(session: &mut Session, lib_path: &str) -> Result<u64> {
+    let bytes = std::fs::read(lib_path)?;
+    let elf = Elf::parse(&bytes).map_err(|e| InjectError::InvalidArgument(format!("{}: {}", lib_path, e)))?;
+    check_library(session, &elf, lib_path)?;
+
+    let loads: Vec<_> = elf.program_headers.iter().filter(|ph| ph.p_type == PT_LOAD).collect();
+    let first = loads.iter().map(|ph| ph.p_vaddr).min().unwrap_or(0) & !PAGE_MASK;
+    let end = loads.iter().map(|ph| ph.p_vaddr + ph.p_memsz).max().unwrap_or(0);
+    let size = ((end - first + PAGE_MASK) & !PAGE_MASK) as usize;
+
+    let base = call_mmap(session, size, PROT_READ | PROT_WRITE)?;
+    let mut image = Image {
+        bytes: vec![0; size],
+        first,
+        bias: base.wrapping_sub(first),
+        word: if elf.is_64 { 8 } else { 4 },
+    };
+    vlog!("[manual] mapping {} at 0x{:x} ({} bytes)", lib_path, base, size);
+
+    let loaded = lay_out(&mut image, &elf, &bytes)
+        .and_then(|_| relocate(session, &mut image, &elf))
+        .and_then(|_| session.memory().write(base, &image.bytes))
+        .and_then(|_| protect(session, &image, &elf));
+    if let Err(e) = loaded {
+        // nothing of it has run yet, it can simply go
+        if !matches!(e, InjectError::RemoteCallCrashed(_) | InjectError::RemoteCallTimedOut { .. }) {
+            let _ = call_munmap(session, base, size);
+        }
+        return Err(e);
+    }
+
+    // from here on the library's own code runs, a crash rolls the mapping back
+    initialize(session, &image, &elf)?;
+    // it stays, whatever the session does later
+    session.freed(base);
+    vlog!("[manual] {} loaded at 0x{:x}", lib_path, base);
+    Ok(base)
+}
+
+/// Address of `symbol` exported by `lib_path`, which `map_library` mapped at `base`
+pub(crate) fn mapped_symbol(lib_path: &str, base: u64, symbol: &str) -> Result<u64> {
+    let bytes = std::fs::read(lib_path)?;
+    let elf = Elf::parse(&bytes).map_err(|e| InjectError::InvalidArgument(format!("{}: {}", lib_path, e)))?;
+    let sym = find_dynamic_symbol(&elf, &bytes, symbol)
+        .ok_or_else(|| InjectError::SymbolNotResolved { module: lib_path.to_string(), symbol: symbol.to_string() })?;
+    Ok(load_bias(&elf, base).wrapping_add(sym.st_value))
+}
+
+fn check_library(session: &Session, elf: &Elf, lib_path: &str) -> Result<()> {
+    let invalid = |what: &str| Err(InjectError::InvalidArgument(format!("{}: {}", lib_path, what)));
+    if elf.header.e_type != ET_DYN {
+        return invalid("not a shared library");
+    }
+    if elf.header.e_machine != expected_machine(session) || elf.is_64 != (session.bits() == 64) {
+        return invalid("built for another architecture than the target");
+    }
+    if elf.program_headers.iter().any(|ph| ph.p_type == PT_TLS) {
+        // Rust's std on glibc always brings some, its Android builds use pthread keys instead
+        return invalid("uses thread-local storage (PT_TLS), which only the dynamic linker can set up; load it with dlopen or --memfd");
+    }
+    let dyns = elf.dynamic.as_ref().map(|d| d.dyns.as_slice()).unwrap_or_default();
+    if dyns.iter().any(|d| d.d_tag == DT_ANDROID_REL || d.d_tag == DT_ANDROID_RELA) {
+        return invalid("has packed (APS2) relocations, relink it without --pack-dyn-relocs=android");
+    }
+    Ok(())
+}
+
+// Copy the file contents of every PT_LOAD, the rest (bss) stays zero
+fn lay_out(image: &mut Image, elf: &Elf, bytes: &[u8]) -> Result<()> {
+    for ph in elf.program_headers.iter().filter(|ph| ph.p_type == PT_LOAD) {
+        let contents = bytes
+            .get(ph.p_offset as usize..(ph.p_offset + ph.p_filesz) as usize)
+            .ok_or_else(|| InjectError::InvalidArgument("segment extends past the end of the file".to_string()))?;
+        let offset = image.offset(ph.p_vaddr, contents.len())?;
+        image.bytes[offset..offset + contents.len()].copy_from_slice(contents);
+    }
+    Ok(())
+}
+
+fn relocate(session: &mut Session, image: &mut Image, elf: &Elf) -> Result<()> {
+    let machine = elf.header.e_machine;
+    let mut imports = Imports::new(session, &elf.libraries);
+    let mut count = 0;
+
+    for reloc in elf.dynrelas.iter().chain(elf.dynrels.iter()).chain(elf.pltrelocs.iter()) {
+        let kind = reloc_kind(machine, reloc.r_type).ok_or_else(|| {
+            InjectError::InvalidArgument(format!("relocation type {} is not supported by the manual loader", reloc.r_type))
+        })?;
+        if kind == RelocKind::None {
+            continue;
+        }
+        // REL keeps the addend in the slot itself
+        let addend = match reloc.r_addend {
+            Some(addend) => addend as u64,
+            None => image.read_word(reloc.r_offset)?,
+        };
+
+        let value = match kind {
+            RelocKind::Relative => image.bias.wrapping_add(addend),
+            _ => {
+                let symbol = symbol_address(session, &mut imports, image, elf, reloc.r_sym)?;
+                match (kind, reloc.r_addend) {
+                    // a REL slot holds no addend, just whatever the PLT stub was
+                    (RelocKind::Slot, None) => symbol,
+                    _ => symbol.wrapping_add(addend),
+                }
+            }
+        };
+        image.write_word(reloc.r_offset, value)?;
+        count += 1;
+    }
+
+    count += apply_relr(image, elf)?;
+    vlog!("[manual] applied {} relocations", count);
+    Ok(())
+}
+
+// Where symbol `index` of the library's .dynsym ends up in the target: its own
+// definition if it has one, otherwise an import. Unresolved weak imports are 0
+fn symbol_address(session: &mut Session, imports: &mut Imports, image: &Image, elf: &Elf, index: usize) -> Result<u64> {
+    if index == 0 {
+        return Ok(0);
+    }
+    let sym = elf
+        .dynsyms
+        .get(index)
+        .ok_or_else(|| InjectError::InvalidArgument(format!("relocation against missing symbol {}", index)))?;
+    if sym.st_shndx != SHN_UNDEF as usize {
+        return Ok(image.bias.wrapping_add(sym.st_value));
+    }
+
+    let name = elf.dynstrtab.get_at(sym.st_name).unwrap_or_default();
+    match imports.lookup(session, name)? {
+        Some(addr) => Ok(addr),
+        None if sym.st_bind() == STB_WEAK => Ok(0),
+        None => Err(InjectError::SymbolNotResolved { module: imports.searched(), symbol: name.to_string() }),
+    }
+}
+
+// Relative relocations packed as RELR: an address entry relocates one word and sets
+// where the following bitmap entries start, each bitmap entry covers the next 63
+// (or 31) words
+fn apply_relr(image: &mut Image, elf: &Elf) -> Result<usize> {
+    let dyns = elf.dynamic.as_ref().map(|d| d.dyns.as_slice()).unwrap_or_default();
+    let tag = |wanted: &[u64]| dyns.iter().find(|d| wanted.contains(&d.d_tag)).map(|d| d.d_val);
+    let (Some(table), Some(size)) = (tag(&[DT_RELR, DT_ANDROID_RELR]), tag(&[DT_RELRSZ, DT_ANDROID_RELRSZ])) else {
+        return Ok(0);
+    };
+
+    let word = image.word as u64;
+    let bits = word * 8;
+    let mut count = 0;
+    let mut relocate = |image: &mut Image, vaddr: u64| -> Result<()> {
+        let value = image.read_word(vaddr)?.wrapping_add(image.bias);
+        count += 1;
+        image.write_word(vaddr, value)
+    };
+
+    let mut next = 0;
+    for i in 0..size / word {
+        let entry = image.read_word(table + i * word)?;
+        if entry & 1 == 0 {
+            relocate(image, entry)?;
+            next = entry + word;
+        } else {
+            for bit in 1..bits {
+                if (entry >> bit) & 1 != 0 {
+                    relocate(image, next + (bit - 1) * word)?;
+                }
+            }
+            next += (bits - 1) * word;
+        }
+    }
+    Ok(count)
+}
+
+// Give every segment its own protection, then make the RELRO part read only
+fn protect(session: &mut Session, image: &Image, elf: &Elf) -> Result<()> {
+    let mprotect = session.resolve(session.libc_path(), "mprotect")?;
+    let mut call = |start: u64, end: u64, prot: i32| -> Result<()> {
+        let start = image.bias.wrapping_add(start) & !PAGE_MASK;
+        let end = image.bias.wrapping_add(end);
+        if end <= start {
+            return Ok(());
+        }
+        let ret = session.call(mprotect, &[start, end - start, prot as u64])? as i32;
+        if ret != 0 {
+            return Err(InjectError::RemoteCallFailed { function: "mprotect".to_string(), ret: ret as i64 });
+        }
+        Ok(())
+    };
+
+    for ph in elf.program_headers.iter().filter(|ph| ph.p_type == PT_LOAD) {
+        call(ph.p_vaddr, (ph.p_vaddr + ph.p_memsz + PAGE_MASK) & !PAGE_MASK, prot_of(ph.p_flags))?;
+    }
+    for ph in elf.program_headers.iter().filter(|ph| ph.p_type == PT_GNU_RELRO) {
+        // the last partial page is shared with writable data
+        call(ph.p_vaddr, (ph.p_vaddr + ph.p_memsz) & !PAGE_MASK, PROT_READ)?;
+    }
+    Ok(())
+}
+
+// Run DT_INIT, then DT_INIT_ARRAY in order, as the linker would
+fn initialize(session: &mut Session, image: &Image, elf: &Elf) -> Result<()> {
+    let dyns = elf.dynamic.as_ref().map(|d| d.dyns.as_slice()).unwrap_or_default();
+    let tag = |wanted: u64| dyns.iter().find(|d| d.d_tag == wanted).map(|d| d.d_val);
+
+    let mut functions = Vec::new();
+    if let Some(init) = tag(DT_INIT) {
+        functions.push(image.bias.wrapping_add(init));
+    }
+    if let (Some(array), Some(size)) = (tag(DT_INIT_ARRAY), tag(DT_INIT_ARRAYSZ)) {
+        // 0 and -1 are placeholders
+        let minus_one = u64::MAX >> (64 - image.word * 8);
+        for i in 0..size / image.word as u64 {
+            let function = image.read_word(array + i * image.word as u64)?;
+            if function != 0 && function != minus_one {
+                functions.push(function);
+            }
+        }
+    }
+
+    for function in functions {
+        vlog!("[manual] running initializer 0x{:x}", function);
+        session.call(function, &[])?;
+    }
+    Ok(())
+}
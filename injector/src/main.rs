@@ -1,44 +1,526 @@
-mod injector;
-mod utils;
-mod ptrace;
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+use std::time::Duration;
 
-#[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
-use injector::inject_library;
-use utils::{disable_selinux, get_pid, is_selinux_enabled};
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+use injector::utils::{disable_selinux, is_selinux_enabled};
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+use injector::packages::{find_package, read_packages_list, PACKAGES_LIST};
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+use injector::process::{find_processes, ProcessFilter, ProcessInfo};
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+use injector::watch::Watcher;
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+use injector::{CallArg, Session};
+use injector::{AttachMode, InjectError, Injector};
 
-#[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} [options] [process name, full path] [library path]", program);
+    eprintln!("       {} eject [options] [process name, full path] [library path]", program);
+    eprintln!("       {} spawn [options] [library path] -- [program] [args...]", program);
+    eprintln!("       {} watch [options] [filters] [library path]", program);
+    eprintln!("       {} zygote [options] [package] [library path]", program);
+    eprintln!("       {} call [options] [pid, process name] [library!symbol] [args...]", program);
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --all-threads   stop every thread of the target while injecting");
+    eprintln!("  --tid <tid>     run the remote calls on this thread instead of the main one");
+    eprintln!("  --seize         attach with PTRACE_SEIZE/PTRACE_INTERRUPT instead of a SIGSTOP");
+    eprintln!("  --kill-on-exit  kill the target if the injector dies while attached");
+    eprintln!("  --timeout <s>   give up on a remote call (e.g. dlopen) after this many seconds");
+    eprintln!("  --memfd         load the library from a memfd copy, the target never opens the path");
+    eprintln!("  --manual-map    map and relocate the library ourselves, without the dynamic linker");
+    eprintln!("                  (libraries with thread-local storage are refused, e.g. libhook built for glibc)");
+    eprintln!("  --config <file> after loading, pass the file's contents to the library's entry point");
+    eprintln!("  --entry <sym>   entry point for --config, default hook_entry");
+    eprintln!("  --errno         call: also print errno as the function left it");
+    eprintln!();
+    eprintln!("Picking among processes of the same name (filters apply to watch too):");
+    eprintln!("  --cmdline <s>   text contained in the command line");
+    eprintln!("  --uid <uid>     real uid of the process");
+    eprintln!("  --user <user>   Android user id (uid / 100000)");
+    eprintln!("  --newest        the most recently started match instead of the lowest pid");
+    eprintln!("  --all           every match, with a per-process summary");
+    eprintln!("  --list          print the matches instead, no library path needed");
+    eprintln!("  --packages-list <path>  where to look up Android package names (default {})", PACKAGES_LIST);
+    eprintln!();
+    eprintln!("Watch (at least one filter):");
+    eprintln!("  --name <name>   process name or executable path");
+    eprintln!("  --every         keep watching and inject every new matching process");
+    eprintln!("  --new-only      ignore matching processes that are already running");
+    std::process::exit(1);
+}
+
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn print_crash_report(e: &InjectError) {
+    if let InjectError::RemoteCallCrashed(report) = e {
+        eprintln!("{}", report);
+        eprintln!("The target's registers were restored and the injector's mappings removed");
+    }
+}
+
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+#[derive(PartialEq)]
+enum Mode {
+    Inject,
+    Eject,
+    Spawn,
+    Watch,
+    Zygote,
+    Call,
+}
+
+// Attach options shared by every mode
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+#[derive(Default)]
+struct Options {
+    all_threads: bool,
+    tid: Option<i32>,
+    attach_mode: AttachMode,
+    kill_on_exit: bool,
+    timeout: Option<Duration>,
+    load: Load,
+    entry: Option<String>,
+    config: Option<Vec<u8>>,
+}
+
+// How the library gets into the target
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+#[derive(Default, PartialEq)]
+enum Load {
+    #[default]
+    Dlopen,
+    Memfd,
+    Manual,
+}
+
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+impl Options {
+    fn configure(&self, injector: Injector) -> Injector {
+        let injector = injector
+            .disable_selinux(true)
+            .stop_all_threads(self.all_threads)
+            .attach_mode(self.attach_mode)
+            .kill_on_exit(self.kill_on_exit);
+        let injector = match self.tid {
+            Some(tid) => injector.thread(tid),
+            None => injector,
+        };
+        let injector = match self.timeout {
+            Some(timeout) => injector.call_timeout(timeout),
+            None => injector,
+        };
+        if self.entry.is_none() && self.config.is_none() {
+            return injector;
+        }
+        let entry = self.entry.as_deref().unwrap_or("hook_entry");
+        injector.entry(entry, self.config.as_deref().unwrap_or_default())
+    }
+
+    fn inject(&self, injector: Injector, library_path: &str) -> Result<u64, InjectError> {
+        match self.load {
+            Load::Dlopen => injector.inject(library_path),
+            Load::Memfd => injector.inject_memfd(library_path),
+            Load::Manual => injector.inject_manual(library_path),
+        }
+    }
+}
+
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 3 {
-        eprintln!("Usage: {} [process name, full path] [library path]", args[0]);
-        std::process::exit(1);
+
+    let (mode, rest) = match args.get(1).map(String::as_str) {
+        Some("eject") => (Mode::Eject, &args[2..]),
+        Some("spawn") => (Mode::Spawn, &args[2..]),
+        Some("watch") => (Mode::Watch, &args[2..]),
+        Some("zygote") => (Mode::Zygote, &args[2..]),
+        Some("call") => (Mode::Call, &args[2..]),
+        _ => (Mode::Inject, &args[1..]),
+    };
+    // spawn: everything after `--` is the command line to start
+    let (rest, command) = match rest.iter().position(|a| a == "--") {
+        Some(i) if mode == Mode::Spawn => (&rest[..i], &rest[i + 1..]),
+        _ if mode == Mode::Spawn => usage(&args[0]),
+        _ => (rest, &[][..]),
+    };
+
+    let mut options = Options::default();
+    let mut filter = ProcessFilter::new();
+    let mut every = false;
+    let mut new_only = false;
+    let mut newest = false;
+    let mut all = false;
+    let mut list = false;
+    let mut packages_list = PACKAGES_LIST;
+    let mut errno = false;
+    let mut positional = Vec::new();
+    let by_name = mode == Mode::Inject || mode == Mode::Eject;
+    let by_filter = by_name || mode == Mode::Watch;
+    let loads = mode != Mode::Eject && mode != Mode::Call;
+    let mut iter = rest.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().map(String::as_str).unwrap_or_else(|| usage(&args[0]));
+        match arg.as_str() {
+            "--all-threads" => options.all_threads = true,
+            "--seize" => options.attach_mode = AttachMode::Seize,
+            "--kill-on-exit" => options.kill_on_exit = true,
+            "--memfd" if loads => options.load = Load::Memfd,
+            "--manual-map" if loads => options.load = Load::Manual,
+            "--entry" if loads => options.entry = Some(value().to_string()),
+            "--config" if loads => {
+                let path = value();
+                match std::fs::read(path) {
+                    Ok(config) => options.config = Some(config),
+                    Err(e) => {
+                        eprintln!("Reading {} failed: {}", path, e);
+                        std::process::exit(InjectError::Io(e).exit_code());
+                    }
+                }
+            }
+            "--timeout" => {
+                let secs: f64 = value().parse().unwrap_or_else(|_| usage(&args[0]));
+                options.timeout = Some(Duration::try_from_secs_f64(secs).unwrap_or_else(|_| usage(&args[0])));
+            }
+            "--tid" => options.tid = Some(value().parse().unwrap_or_else(|_| usage(&args[0]))),
+            "--name" if mode == Mode::Watch => filter = filter.name(value()),
+            "--cmdline" if by_filter => filter = filter.cmdline(value()),
+            "--uid" if by_filter => filter = filter.uid(value().parse().unwrap_or_else(|_| usage(&args[0]))),
+            "--user" if by_filter => filter = filter.user(value().parse().unwrap_or_else(|_| usage(&args[0]))),
+            "--every" if mode == Mode::Watch => every = true,
+            "--new-only" if mode == Mode::Watch => new_only = true,
+            "--newest" if by_name => newest = true,
+            "--all" if by_name => all = true,
+            "--list" if by_name => list = true,
+            "--packages-list" if by_name => packages_list = value(),
+            "--errno" if mode == Mode::Call => errno = true,
+            _ => positional.push(arg),
+        }
+    }
+    let expected = match mode {
+        Mode::Inject | Mode::Eject if list => 1,
+        Mode::Inject | Mode::Eject | Mode::Zygote => 2,
+        Mode::Spawn | Mode::Watch => 1,
+        // the function's own arguments follow
+        Mode::Call => positional.len().max(2),
+    };
+    if positional.len() != expected
+        || (mode == Mode::Spawn && command.is_empty())
+        || (mode == Mode::Watch && filter.is_empty())
+        || (newest && all)
+    {
+        usage(&args[0]);
     }
 
-    let process_name = &args[1];
-    let library_path = &args[2];
+    if mode == Mode::Watch {
+        watch(Watcher::new(filter).new_only(new_only), every, &options, positional[0]);
+        return;
+    }
 
-    if let Some(pid) = get_pid(process_name) {
-        #[cfg(debug_assertions)]
-        println!("process name: {}, library path: {}, pid: {}", process_name, library_path, pid);
+    if mode == Mode::Call {
+        let pid = match positional[0].parse() {
+            Ok(pid) => pid,
+            Err(_) => {
+                let processes = find_processes(&target_filter(filter, positional[0], packages_list));
+                select(processes, positional[0], false, false)[0].pid
+            }
+        };
+        let Some((module, symbol)) = positional[1].split_once('!') else {
+            usage(&args[0]);
+        };
+        let call_args: Vec<CallArg> = positional[2..].iter().map(|arg| parse_call_arg(arg)).collect();
+        if let Err(e) = call(options.configure(Injector::new(pid)), module, symbol, &call_args, errno) {
+            eprintln!("Call failed: {}", e);
+            print_crash_report(&e);
+            std::process::exit(e.exit_code());
+        }
+        return;
+    }
 
-        if is_selinux_enabled() {
-            disable_selinux();
+    if by_name {
+        let matches = find_processes(&target_filter(filter, positional[0], packages_list));
+        if list {
+            print_processes(&matches);
+            return;
+        }
+        run_on(&mode, select(matches, positional[0], newest, all), &options, positional[1]);
+        return;
+    }
+
+    let (process_name, library_path) =
+        if mode == Mode::Spawn { (&command[0], positional[0]) } else { (positional[0], positional[1]) };
+
+    let found = match mode {
+        Mode::Spawn => {
+            let program_args: Vec<&str> = command[1..].iter().map(String::as_str).collect();
+            Injector::spawn(process_name, &program_args)
+        }
+        _ => {
+            // zygote is traced before the injector options come into play
+            if is_selinux_enabled() {
+                disable_selinux();
+            }
+            println!("Waiting for {} to be started", process_name);
+            Injector::from_zygote(process_name)
+        }
+    };
+    let injector = match found {
+        Ok(injector) => options.configure(injector),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code());
         }
+    };
 
-        let result = inject_library(pid, library_path);
+    #[cfg(debug_assertions)]
+    println!("process name: {}, library path: {}, pid: {}", process_name, library_path, injector.pid());
+
+    if let Err(e) = run(&mode, injector, &options, library_path) {
+        std::process::exit(e.exit_code());
+    }
+}
+
+// Inject into (or eject from) one target, reporting how it went
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn run(mode: &Mode, injector: Injector, options: &Options, library_path: &str) -> Result<(), InjectError> {
+    if *mode == Mode::Eject {
+        return match injector.eject(library_path) {
+            Ok(()) => {
+                println!("Ejected {}", library_path);
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("Eject failed: {}", e);
+                print_crash_report(&e);
+                Err(e)
+            }
+        };
+    }
+
+    match options.inject(injector, library_path) {
+        // the linker doesn't know a manually mapped library, all there is is where it went
+        Ok(base) if options.load == Load::Manual => {
+            println!("Injection succeeded, library mapped at: 0x{:x}", base);
+            Ok(())
+        }
+        Ok(handle) => {
+            println!("Injection succeeded with handle: 0x{:x}", handle);
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Injection failed: {}", e);
+            print_crash_report(&e);
+            Err(e)
+        }
+    }
+}
+
+// Narrow the matches down to the processes to work on: all of them, the newest, or
+// the lowest pid as a plain name lookup always did
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn select(mut matches: Vec<ProcessInfo>, process_name: &str, newest: bool, all: bool) -> Vec<ProcessInfo> {
+    if matches.is_empty() {
+        let e = InjectError::ProcessNotFound(process_name.to_string());
+        eprintln!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+    if newest {
+        let newest = matches.iter().enumerate().max_by_key(|(_, p)| p.start_time).map(|(i, _)| i).unwrap_or(0);
+        return vec![matches.swap_remove(newest)];
+    }
+    if !all && matches.len() > 1 {
+        eprintln!(
+            "{} processes match {}, using pid {} (see --list, --newest and --all)",
+            matches.len(),
+            process_name,
+            matches[0].pid
+        );
+        matches.truncate(1);
+    }
+    matches
+}
+
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn run_on(mode: &Mode, targets: Vec<ProcessInfo>, options: &Options, library_path: &str) {
+    if let [target] = targets.as_slice() {
+        #[cfg(debug_assertions)]
+        println!("process name: {}, library path: {}, pid: {}", target.name, library_path, target.pid);
+        if let Err(e) = run(mode, options.configure(Injector::new(target.pid)), options, library_path) {
+            std::process::exit(e.exit_code());
+        }
+        return;
+    }
+
+    let mut results = Vec::new();
+    for target in &targets {
+        println!("== pid {} ({}) ==", target.pid, target.cmdline.join(" "));
+        results.push((target.pid, run(mode, options.configure(Injector::new(target.pid)), options, library_path)));
+    }
+
+    println!();
+    println!("Summary:");
+    for (pid, result) in &results {
         match result {
-            Ok(0) => println!("Injection returned 0 (likely failed)..."),
-            Ok(handle) => println!("Injection succeeded with handle: 0x{:x}", handle),
-            Err(e) => eprintln!("Injection failed: {}", e),
+            Ok(()) => println!("  {:>7}  ok", pid),
+            Err(e) => println!("  {:>7}  failed: {}", pid, e),
+        }
+    }
+    let failed: Vec<_> = results.iter().filter_map(|(_, r)| r.as_ref().err()).collect();
+    println!("{} of {} succeeded", results.len() - failed.len(), results.len());
+    if let Some(e) = failed.first() {
+        std::process::exit(e.exit_code());
+    }
+}
+
+// A name listed in packages.list is an Android package, its processes are found by uid
+// and process name (comm is too short for most package names). Anything else, or no
+// readable list as on a desktop Linux, is a plain process name
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn target_filter(filter: ProcessFilter, name: &str, packages_list: &str) -> ProcessFilter {
+    let packages = read_packages_list(packages_list).unwrap_or_default();
+    match find_package(&packages, name) {
+        Some(package) => {
+            eprintln!("{} is an installed package, uid {}", package.name, package.uid);
+            filter.package(package)
         }
-        
+        None => filter.name(name),
+    }
+}
+
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn print_processes(processes: &[ProcessInfo]) {
+    println!("{:>7} {:>7} {:>7} {:>4} {:>4}  {:<32} CMDLINE", "PID", "PPID", "UID", "USER", "BITS", "CONTEXT");
+    for p in processes {
+        let bits = p.bits.map(|b| b.to_string()).unwrap_or_else(|| "?".to_string());
+        let context = p.selinux_context.as_deref().unwrap_or("-");
+        println!(
+            "{:>7} {:>7} {:>7} {:>4} {:>4}  {:<32} {}",
+            p.pid,
+            p.ppid,
+            p.uid,
+            p.android_user(),
+            bits,
+            context,
+            p.cmdline.join(" ")
+        );
+    }
+}
+
+// `"text"` (quotes included, so quote them for the shell too) is always a string,
+// otherwise decimal, 0x hex and negative numbers are integers and anything else a string
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn parse_call_arg(arg: &str) -> CallArg {
+    if let Some(text) = arg.strip_prefix('"').and_then(|a| a.strip_suffix('"')) {
+        return CallArg::Str(text.to_string());
+    }
+    let number = match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => arg.parse::<u64>().ok().or_else(|| arg.parse::<i64>().ok().map(|v| v as u64)),
+    };
+    match number {
+        Some(value) => CallArg::Int(value),
+        None => CallArg::Str(arg.to_string()),
+    }
+}
+
+// Run module!symbol in the target and print what it returned, errno too if asked for
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn call(injector: Injector, module: &str, symbol: &str, args: &[CallArg], errno: bool) -> Result<(), InjectError> {
+    let mut session = injector.attach()?;
+    let result = call_in(&mut session, module, symbol, args, errno);
+    let bits = session.bits();
+    let detached = session.detach();
+    let (ret, errno) = result?;
+
+    // the return value only has the target's word size
+    let signed = if bits == 32 { ret as u32 as i32 as i64 } else { ret as i64 };
+    // an int return only sets the low half of a 64-bit register
+    if bits == 64 && ret >> 32 == 0 && (ret as u32 as i32) < 0 {
+        println!("{}!{} returned {} (0x{:x}, int {})", module, symbol, signed, ret, ret as u32 as i32);
     } else {
-        eprintln!("Process not found: {}", process_name);
+        println!("{}!{} returned {} (0x{:x})", module, symbol, signed, ret);
+    }
+    if let Some(errno) = errno {
+        println!("errno: {}", std::io::Error::from_raw_os_error(errno));
+    }
+    // the call happened either way, so what it returned is worth printing first
+    detached
+}
+
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn call_in(
+    session: &mut Session,
+    module: &str,
+    symbol: &str,
+    args: &[CallArg],
+    errno: bool,
+) -> Result<(u64, Option<i32>), InjectError> {
+    let func = session.resolve_function(module, symbol)?;
+    if errno {
+        session.set_errno(0)?;
+    }
+    let ret = session.call_args(func, args)?;
+    let errno = if errno { Some(session.errno()?) } else { None };
+    Ok((ret, errno))
+}
+
+// Inject into the first matching process, or with `every` into each one that shows up
+// until we get killed. A failed injection only ends the watch when it was the one shot
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn watch(mut watcher: Watcher, every: bool, options: &Options, library_path: &str) {
+    loop {
+        let pid = match watcher.next_match() {
+            Ok(pid) => pid,
+            Err(e) => {
+                eprintln!("Watching failed: {}", e);
+                std::process::exit(e.exit_code());
+            }
+        };
+        println!("Found matching process {}", pid);
+
+        match inject_when_loaded(options, pid, library_path) {
+            Ok(handle) => println!("Injected into {} with handle: 0x{:x}", pid, handle),
+            Err(e) => {
+                eprintln!("Injection into {} failed: {}", pid, e);
+                print_crash_report(&e);
+                if !every {
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        if !every {
+            return;
+        }
+    }
+}
+
+// 10ms doubling up to 160ms, about a third of a second in all
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+const WATCH_RETRIES: u32 = 5;
+
+// A process the watcher just caught may have its libc mapped while the dynamic linker
+// is still setting it up, so the functions we need can't be found or mmap fails. Those
+// are given a few more tries while the loader finishes. A failed dlopen or a crash is
+// reported right away: retrying a missing dependency or a crashing constructor would
+// run it again in the live target, and each rolled back crash leaves its locks held
+#[cfg(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86"))]
+fn inject_when_loaded(options: &Options, pid: i32, library_path: &str) -> Result<u64, InjectError> {
+    let mut delay = Duration::from_millis(10);
+    for _ in 0..WATCH_RETRIES {
+        match options.inject(options.configure(Injector::new(pid)), library_path) {
+            Err(e @ (InjectError::SymbolNotResolved { .. } | InjectError::RemoteAllocFailed { .. })) => {
+                eprintln!("Injection into {} failed ({}), retrying in {:?}", pid, e, delay);
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
     }
+    options.inject(options.configure(Injector::new(pid)), library_path)
 }
 
-#[cfg(not(any(target_arch = "aarch64", target_arch = "arm")))]
+#[cfg(not(any(target_arch = "aarch64", target_arch = "arm", target_arch = "x86_64", target_arch = "x86")))]
 fn main() {
-    eprintln!("This injector only supports aarch64 and arm architectures");
+    eprintln!("This injector only supports aarch64, arm, x86_64 and x86 architectures");
     std::process::exit(1);
 }
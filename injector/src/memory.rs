@@ -0,0 +1,162 @@
+use std::fs::OpenOptions;
+use std::io::{self, Error};
+use std::os::unix::fs::FileExt;
+
+use libc::{c_void, iovec, pid_t};
+
+use crate::error::{InjectError, Result};
+use crate::ptrace::{ptrace_peek_word, ptrace_poke_word};
+
+// Upper bound for strings pulled out of the target, guards against unterminated garbage
+const MAX_CSTRING_LEN: usize = 0x10000;
+
+/// Bulk access to another process' memory
+///
+/// Every operation tries `process_vm_readv/writev` first, then `/proc/<pid>/mem`
+/// and finally word sized PEEK/POKE, which only works while the target is
+/// ptrace-stopped. The first two respect page protections differently: the
+/// vm calls fail on pages the target itself couldn't access, `/proc/<pid>/mem`
+/// writes through them (which is what we want for patching code)
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteMemory {
+    pid: pid_t,
+}
+
+impl RemoteMemory {
+    pub fn new(pid: pid_t) -> Self {
+        RemoteMemory { pid }
+    }
+
+    pub fn read(&self, addr: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        if len == 0 {
+            return Ok(buf);
+        }
+
+        let err = match self.vm_read(addr, &mut buf) {
+            Ok(()) => return Ok(buf),
+            Err(e) => e,
+        };
+        vlog!("[mem] process_vm_readv 0x{:x}+{} failed ({}), trying /proc/{}/mem", addr, len, err, self.pid);
+
+        let err = match self.proc_mem_read(addr, &mut buf) {
+            Ok(()) => return Ok(buf),
+            Err(e) => e,
+        };
+        vlog!("[mem] /proc/{}/mem read failed ({}), falling back to PEEKDATA", self.pid, err);
+
+        self.peek_read(addr, &mut buf)
+            .map(|_| buf)
+            .map_err(|source| InjectError::MemoryAccess { addr, len, source })
+    }
+
+    pub fn write(&self, addr: u64, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let err = match self.vm_write(addr, data) {
+            Ok(()) => return self.wrote(addr, data.len()),
+            Err(e) => e,
+        };
+        vlog!("[mem] process_vm_writev 0x{:x}+{} failed ({}), trying /proc/{}/mem", addr, data.len(), err, self.pid);
+
+        let err = match self.proc_mem_write(addr, data) {
+            Ok(()) => return self.wrote(addr, data.len()),
+            Err(e) => e,
+        };
+        vlog!("[mem] /proc/{}/mem write failed ({}), falling back to POKEDATA", self.pid, err);
+
+        self.poke_write(addr, data)
+            .map_err(|source| InjectError::MemoryAccess { addr, len: data.len(), source })?;
+        self.wrote(addr, data.len())
+    }
+
+    /// Read a NUL terminated string starting at `addr`
+    pub fn read_cstring(&self, addr: u64) -> Result<String> {
+        let mut bytes = Vec::new();
+        let mut cur = addr;
+
+        // Never read across a page boundary in one go, the next page may not exist
+        while bytes.len() < MAX_CSTRING_LEN {
+            let chunk = (PAGE_SIZE - (cur % PAGE_SIZE)) as usize;
+            let data = self.read(cur, chunk)?;
+            if let Some(nul) = data.iter().position(|b| *b == 0) {
+                bytes.extend_from_slice(&data[..nul]);
+                return Ok(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            bytes.extend_from_slice(&data);
+            cur += chunk as u64;
+        }
+
+        bytes.truncate(MAX_CSTRING_LEN);
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn wrote(&self, _addr: u64, _len: usize) -> Result<()> {
+        #[cfg(debug_assertions)]
+        eprintln!("[mem] Wrote {} bytes to 0x{:x}", _len, _addr);
+        Ok(())
+    }
+
+    fn vm_read(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        let local = iovec { iov_base: buf.as_mut_ptr() as *mut c_void, iov_len: buf.len() };
+        let remote = iovec { iov_base: addr as usize as *mut c_void, iov_len: buf.len() };
+        let n = unsafe { libc::process_vm_readv(self.pid, &local, 1, &remote, 1, 0) };
+        check_transfer(n, buf.len())
+    }
+
+    fn vm_write(&self, addr: u64, data: &[u8]) -> io::Result<()> {
+        let local = iovec { iov_base: data.as_ptr() as *mut c_void, iov_len: data.len() };
+        let remote = iovec { iov_base: addr as usize as *mut c_void, iov_len: data.len() };
+        let n = unsafe { libc::process_vm_writev(self.pid, &local, 1, &remote, 1, 0) };
+        check_transfer(n, data.len())
+    }
+
+    fn proc_mem_read(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        let f = OpenOptions::new().read(true).open(format!("/proc/{}/mem", self.pid))?;
+        f.read_exact_at(buf, addr)
+    }
+
+    fn proc_mem_write(&self, addr: u64, data: &[u8]) -> io::Result<()> {
+        let f = OpenOptions::new().write(true).open(format!("/proc/{}/mem", self.pid))?;
+        f.write_all_at(data, addr)
+    }
+
+    fn peek_read(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        let word_size = size_of::<usize>();
+        for (i, chunk) in buf.chunks_mut(word_size).enumerate() {
+            let word = ptrace_peek_word(self.pid, addr + (i * word_size) as u64)?.to_ne_bytes();
+            chunk.copy_from_slice(&word[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn poke_write(&self, addr: u64, data: &[u8]) -> io::Result<()> {
+        let word_size = size_of::<usize>();
+        for (i, chunk) in data.chunks(word_size).enumerate() {
+            let word_addr = addr + (i * word_size) as u64;
+            let mut word = if chunk.len() == word_size {
+                [0u8; size_of::<usize>()]
+            } else {
+                // Keep whatever follows a partial tail word
+                ptrace_peek_word(self.pid, word_addr)?.to_ne_bytes()
+            };
+            word[..chunk.len()].copy_from_slice(chunk);
+            ptrace_poke_word(self.pid, word_addr, usize::from_ne_bytes(word))?;
+        }
+        Ok(())
+    }
+}
+
+const PAGE_SIZE: u64 = 0x1000;
+
+fn check_transfer(n: isize, expected: usize) -> io::Result<()> {
+    if n < 0 {
+        return Err(Error::last_os_error());
+    }
+    if n as usize != expected {
+        return Err(Error::new(io::ErrorKind::UnexpectedEof, format!("short transfer {} of {} bytes", n, expected)));
+    }
+    Ok(())
+}
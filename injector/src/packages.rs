@@ -0,0 +1,74 @@
+//! Android package names, resolved through the package manager's packages.list
+//!
+//! comm is cut at 15 characters and most package names are longer, so a package is
+//! better found by its uid and the process names Android gives its processes: the
+//! package itself, or `package:name` for extra processes.
+
+use std::path::Path;
+
+use crate::error::Result;
+use crate::process::AID_USER_OFFSET;
+
+/// Where the package manager keeps the list on a device
+pub const PACKAGES_LIST: &str = "/data/system/packages.list";
+
+/// One line of packages.list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageInfo {
+    pub name: String,
+    /// uid for the owner (user 0), also the app id
+    pub uid: u32,
+    pub debuggable: bool,
+    pub data_dir: String,
+    pub seinfo: String,
+}
+
+impl PackageInfo {
+    /// Whether a process running as `uid` with argv[0] `process_name` belongs to the
+    /// package, for any Android user. Packages sharing a uid are told apart by the
+    /// process name
+    pub fn owns_process(&self, uid: u32, process_name: &str) -> bool {
+        // the app id is the uid within the user's range
+        uid % AID_USER_OFFSET == self.uid % AID_USER_OFFSET
+            && process_name.strip_prefix(self.name.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with(':'))
+    }
+}
+
+/// Parse packages.list, one package per line:
+/// `name uid debuggable data_dir seinfo gids [profileable version ...]`.
+/// Lines that don't fit are skipped
+pub fn parse_packages_list(contents: &str) -> Vec<PackageInfo> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let package = parse_line(line);
+            if package.is_none() && !line.trim().is_empty() {
+                vlog!("[packages] skipping malformed line '{}'", line);
+            }
+            package
+        })
+        .collect()
+}
+
+// None for lines with fewer than five fields or a uid that isn't a number
+fn parse_line(line: &str) -> Option<PackageInfo> {
+    let mut fields = line.split_whitespace();
+    Some(PackageInfo {
+        name: fields.next()?.to_string(),
+        uid: fields.next()?.parse().ok()?,
+        debuggable: fields.next()? != "0",
+        data_dir: fields.next()?.to_string(),
+        seinfo: fields.next()?.to_string(),
+    })
+}
+
+/// Read and parse the list at `path`, usually `PACKAGES_LIST`
+pub fn read_packages_list(path: impl AsRef<Path>) -> Result<Vec<PackageInfo>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(parse_packages_list(&contents))
+}
+
+/// Look `name` up in `packages`
+pub fn find_package<'a>(packages: &'a [PackageInfo], name: &str) -> Option<&'a PackageInfo> {
+    packages.iter().find(|p| p.name == name)
+}
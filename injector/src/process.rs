@@ -0,0 +1,153 @@
+//! Finding processes: every match with the details needed to tell them apart
+//!
+//! A name alone is often ambiguous, think of the same app running for several Android
+//! users or an app's `:remote` service processes.
+
+use libc::pid_t;
+
+use crate::packages::PackageInfo;
+use crate::utils::{
+    get_cmdline, get_parent_pid, get_process_bitness, get_process_uid, get_selinux_context, get_start_time,
+    process_name_matches,
+};
+
+// Android gives every user a range of 100000 uids, uid / 100000 is the user id
+pub(crate) const AID_USER_OFFSET: u32 = 100_000;
+
+/// Which processes to look for (`find_processes`) or wait for (`watch::Watcher`),
+/// every criterion given has to match
+#[derive(Debug, Clone, Default)]
+pub struct ProcessFilter {
+    name: Option<String>,
+    cmdline: Option<String>,
+    uid: Option<u32>,
+    user: Option<u32>,
+    package: Option<PackageInfo>,
+}
+
+impl ProcessFilter {
+    pub fn new() -> Self {
+        ProcessFilter::default()
+    }
+
+    /// Process name or executable path, compared like `utils::get_pid` does
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Text to look for in the command line, arguments joined with spaces
+    pub fn cmdline(mut self, pattern: &str) -> Self {
+        self.cmdline = Some(pattern.to_string());
+        self
+    }
+
+    /// Real uid the process runs as
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Android user (0 for the owner, 10 and up for secondary users and work profiles)
+    pub fn user(mut self, user: u32) -> Self {
+        self.user = Some(user);
+        self
+    }
+
+    /// Processes of an Android package, found by uid and process name so names
+    /// longer than comm holds work too, see `PackageInfo::owns_process`
+    pub fn package(mut self, package: &PackageInfo) -> Self {
+        self.package = Some(package.clone());
+        self
+    }
+
+    /// No criterion at all, which would match every process
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.cmdline.is_none() && self.uid.is_none() && self.user.is_none() && self.package.is_none()
+    }
+
+    pub fn matches(&self, pid: pid_t) -> bool {
+        if self.uid.is_some() || self.user.is_some() || self.package.is_some() {
+            let Some(uid) = get_process_uid(pid) else {
+                return false;
+            };
+            if self.uid.is_some_and(|wanted| wanted != uid)
+                || self.user.is_some_and(|wanted| wanted != uid / AID_USER_OFFSET)
+            {
+                return false;
+            }
+            if let Some(package) = &self.package {
+                let name = get_cmdline(pid).and_then(|args| args.into_iter().next()).unwrap_or_default();
+                if !package.owns_process(uid, &name) {
+                    return false;
+                }
+            }
+        }
+        if let Some(pattern) = &self.cmdline {
+            if !get_cmdline(pid).is_some_and(|args| args.join(" ").contains(pattern.as_str())) {
+                return false;
+            }
+        }
+        if let Some(name) = &self.name {
+            if !process_name_matches(pid, name) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// What we know about a process
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: pid_t,
+    pub ppid: pid_t,
+    /// Real uid
+    pub uid: u32,
+    /// Short name from /proc/<pid>/comm
+    pub name: String,
+    pub cmdline: Vec<String>,
+    /// Word size, None if it can't be told
+    pub bits: Option<u32>,
+    /// SELinux context (or whatever the active LSM reports), None without one
+    pub selinux_context: Option<String>,
+    /// Clock ticks since boot, larger is newer
+    pub start_time: u64,
+}
+
+impl ProcessInfo {
+    /// None if `pid` is gone or can't be inspected
+    pub fn read(pid: pid_t) -> Option<Self> {
+        let name = std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+        Some(ProcessInfo {
+            pid,
+            ppid: get_parent_pid(pid)?,
+            uid: get_process_uid(pid)?,
+            name: name.trim_end().to_string(),
+            cmdline: get_cmdline(pid).unwrap_or_default(),
+            bits: get_process_bitness(pid),
+            selinux_context: get_selinux_context(pid),
+            start_time: get_start_time(pid)?,
+        })
+    }
+
+    /// Android user the process belongs to, 0 for the owner and system processes
+    pub fn android_user(&self) -> u32 {
+        self.uid / AID_USER_OFFSET
+    }
+}
+
+/// Every process matching `filter`, by ascending pid
+pub fn find_processes(filter: &ProcessFilter) -> Vec<ProcessInfo> {
+    list_pids().into_iter().filter(|&pid| filter.matches(pid)).filter_map(ProcessInfo::read).collect()
+}
+
+/// Pids of all processes, ascending
+pub(crate) fn list_pids() -> Vec<pid_t> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let mut pids: Vec<pid_t> = entries.flatten().filter_map(|e| e.file_name().to_string_lossy().parse().ok()).collect();
+    pids.sort_unstable();
+    pids
+}
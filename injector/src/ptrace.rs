@@ -1,11 +1,21 @@
 use libc::{c_void, pid_t};
-use std::io::{Error, Result};
-use std::mem::{size_of, zeroed};
+use std::io::Error;
+use std::mem::zeroed;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
 use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use nix::sys::wait::{waitpid, WaitStatus};
+use nix::errno::Errno;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 
+use crate::error::{CrashReport, InjectError, Result};
+use crate::memory::RemoteMemory;
+use crate::utils::get_thread_ids;
+
 #[cfg(target_arch = "aarch64")]
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
@@ -17,102 +27,521 @@ pub struct PtRegs {
     pub pstate: u64,     // processor state
 }
 
+// Matches struct pt_regs armv7, which is also the NT_PRSTATUS view an aarch64
+// kernel gives of 32-bit (compat) tasks
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ArmRegs {
+    pub uregs: [u32; 18],
+}
+
 #[cfg(target_arch = "arm")]
+pub type PtRegs = ArmRegs;
+
+#[cfg(target_arch = "x86_64")]
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct PtRegs {
-    // Matches struct pt_regs armv7
-    pub uregs: [u32; 18],
+    // Matches struct user_regs_struct x86_64
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub orig_rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub eflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+    pub ds: u64,
+    pub es: u64,
+    pub fs: u64,
+    pub gs: u64,
 }
 
-pub fn ptrace_attach(pid: pid_t) -> Result<()> {
-    if unsafe { libc::ptrace(libc::PTRACE_ATTACH, pid, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) } < 0 {
-        return Err(Error::last_os_error());
+#[cfg(target_arch = "x86")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PtRegs {
+    // Matches struct user_regs_struct i386
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub esi: u32,
+    pub edi: u32,
+    pub ebp: u32,
+    pub eax: u32,
+    pub xds: u32,
+    pub xes: u32,
+    pub xfs: u32,
+    pub xgs: u32,
+    pub orig_eax: u32,
+    pub eip: u32,
+    pub xcs: u32,
+    pub eflags: u32,
+    pub esp: u32,
+    pub xss: u32,
+}
+
+/// Per-target state the remote call machinery keeps between calls
+#[derive(Debug)]
+pub struct Tracee {
+    // thread the remote calls run on, the process' pid unless another thread was picked
+    pub pid: pid_t,
+    // 32-bit target driven from a 64-bit injector
+    pub compat: bool,
+    // attached with PTRACE_SEIZE, which allows PTRACE_INTERRUPT
+    pub seized: bool,
+    // how long a single remote call may run before it gets interrupted
+    pub call_timeout: Option<Duration>,
+    // signals meant for the target that arrived during our calls, re-sent on detach
+    pending_signals: Vec<Signal>,
+    // Page holding our call/syscall stubs, 0 until bootstrapped
+    #[cfg_attr(any(target_arch = "arm", target_arch = "x86"), allow(dead_code))]
+    stub_page: u64,
+}
+
+impl Tracee {
+    pub fn new(pid: pid_t, compat: bool) -> Self {
+        Tracee { pid, compat, seized: false, call_timeout: None, pending_signals: Vec::new(), stub_page: 0 }
     }
-    let _ = waitpid(Pid::from_raw(pid), None);
+
+    /// Signals held back while remote calls were running, oldest first
+    pub fn take_pending_signals(&mut self) -> Vec<Signal> {
+        std::mem::take(&mut self.pending_signals)
+    }
+}
+
+// Not exported by libc for every target we build for (bionic lacks them)
+const PTRACE_SEIZE: i32 = 0x4206;
+const PTRACE_INTERRUPT: i32 = 0x4207;
+pub(crate) const PTRACE_EVENT_STOP: i32 = 128;
+
+/// How the target gets stopped when we attach
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AttachMode {
+    /// PTRACE_ATTACH, stops the target with a SIGSTOP it can observe and which
+    /// interferes with job control
+    #[default]
+    Attach,
+    /// PTRACE_SEIZE followed by PTRACE_INTERRUPT, no signal is sent at all
+    Seize,
+}
+
+/// Attach to `pid` and wait until it is stopped for us. `options` are PTRACE_O_* flags,
+/// e.g. PTRACE_O_EXITKILL so the target dies with us rather than being left hijacked
+pub fn ptrace_attach(pid: pid_t, mode: AttachMode, options: i32) -> Result<()> {
+    match mode {
+        AttachMode::Attach => {
+            if unsafe { libc::ptrace(libc::PTRACE_ATTACH, pid, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) } < 0 {
+                return Err(attach_error(pid));
+            }
+        }
+        AttachMode::Seize => ptrace_seize(pid, options)?,
+    }
+
+    let stopped = if mode == AttachMode::Seize {
+        ptrace_interrupt(pid).and_then(|_| wait_for_attach_stop(pid, mode))
+    } else {
+        wait_for_attach_stop(pid, mode)
+    };
+    // seize took the options along already
+    let stopped = match stopped {
+        Ok(()) if mode == AttachMode::Attach && options != 0 => ptrace_set_options(pid, options),
+        other => other,
+    };
+    if let Err(e) = stopped {
+        if !matches!(e, InjectError::ProcessExited { .. }) {
+            let _ = ptrace_detach(pid);
+        }
+        return Err(e);
+    }
+
     #[cfg(debug_assertions)]
-    eprintln!("[ptrace] Attached to {}", pid);
+    eprintln!("[ptrace] Attached to {} ({:?})", pid, mode);
     Ok(())
 }
 
-pub fn ptrace_detach(pid: pid_t) -> Result<()> {
-    if unsafe { libc::ptrace(libc::PTRACE_DETACH, pid, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) } < 0 {
-        return Err(Error::last_os_error());
+/// PTRACE_SEIZE `pid` and leave it running, it only stops for the events `options`
+/// ask for or once interrupted
+pub(crate) fn ptrace_seize(pid: pid_t, options: i32) -> Result<()> {
+    if unsafe { libc::ptrace(PTRACE_SEIZE as _, pid, ptr::null_mut::<c_void>(), options as usize as *mut c_void) } < 0 {
+        return Err(attach_error(pid));
     }
-    #[cfg(debug_assertions)]
-    eprintln!("[ptrace] Detached from {}", pid);
     Ok(())
 }
 
-pub fn ptrace_peek_word(pid: pid_t, addr: *mut c_void) -> usize {
-    // Deliberately ignore errno weirdness (-1 vs error) to avoid errno access on android
-    unsafe { libc::ptrace(libc::PTRACE_PEEKDATA, pid, addr, ptr::null_mut::<c_void>()) as usize }
+fn attach_error(pid: pid_t) -> InjectError {
+    let e = Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ESRCH) => InjectError::ProcessNotFound(pid.to_string()),
+        _ => InjectError::AttachDenied { pid, source: e },
+    }
 }
 
-fn ptrace_poke_word(pid: pid_t, addr: *mut c_void, data: usize) -> Result<()> {
-    let ret = unsafe { libc::ptrace(libc::PTRACE_POKEDATA, pid, addr, data) };
-    if ret < 0 {
-        return Err(Error::last_os_error());
+pub(crate) fn ptrace_interrupt(pid: pid_t) -> Result<()> {
+    if unsafe { libc::ptrace(PTRACE_INTERRUPT as _, pid, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) } < 0 {
+        return Err(Error::last_os_error().into());
     }
     Ok(())
 }
 
-pub fn ptrace_write(pid: pid_t, addr: *mut u8, data: &[u8]) -> Result<()> {
-    let word_size = size_of::<usize>();
-    let mut offset = 0;
+pub(crate) fn ptrace_set_options(pid: pid_t, options: i32) -> Result<()> {
+    if unsafe { libc::ptrace(libc::PTRACE_SETOPTIONS, pid, ptr::null_mut::<c_void>(), options as usize as *mut c_void) } < 0 {
+        return Err(Error::last_os_error().into());
+    }
+    Ok(())
+}
 
-    // Full words
-    while offset + word_size <= data.len() {
-        let word = usize::from_ne_bytes(data[offset..offset + word_size].try_into().unwrap());
-        ptrace_poke_word(pid, unsafe { addr.add(offset) } as *mut c_void, word)?;
-        offset += word_size;
+/// Start `program` as our tracee (PTRACE_TRACEME). Returns once the exec went through,
+/// with the new image stopped on its post-exec SIGTRAP before the dynamic loader ran
+pub fn spawn_traced(program: &str, args: &[&str]) -> Result<pid_t> {
+    let mut command = Command::new(program);
+    command.args(args);
+    unsafe {
+        command.pre_exec(|| {
+            if libc::ptrace(libc::PTRACE_TRACEME, 0, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) < 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        });
     }
+    // the child is reaped by waitpid, never through the handle
+    let pid = command
+        .spawn()
+        .map_err(|e| Error::new(e.kind(), format!("failed to start {}: {}", program, e)))?
+        .id() as pid_t;
 
-    // Leftovers
-    if offset < data.len() {
-        let tail_addr = unsafe { addr.add(offset) } as *mut c_void;
-        let mut word = ptrace_peek_word(pid, tail_addr);
-        let ptr_u8 = &mut word as *mut _ as *mut u8;
-        let tail_len = data.len() - offset;
-        for i in 0..tail_len {
-            unsafe { *ptr_u8.add(i) = data[offset + i]; }
+    match waitpid(Pid::from_raw(pid), None) {
+        Ok(WaitStatus::Stopped(_, Signal::SIGTRAP)) => {
+            #[cfg(debug_assertions)]
+            eprintln!("[ptrace] Spawned {} as {}", program, pid);
+            Ok(pid)
+        }
+        Ok(WaitStatus::Exited(_, code)) => Err(InjectError::ProcessExited { pid, code: Some(code), signal: None }),
+        Ok(WaitStatus::Signaled(_, sig, _)) => Err(InjectError::ProcessExited { pid, code: None, signal: Some(sig) }),
+        Ok(status) => {
+            kill_spawned(pid);
+            Err(Error::other(format!("unexpected stop after starting {}: {:?}", program, status)).into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Get rid of a target started by `spawn_traced` that isn't going to be handed back
+pub(crate) fn kill_spawned(pid: pid_t) {
+    unsafe { libc::kill(pid, libc::SIGKILL) };
+    let _ = waitpid(Pid::from_raw(pid), None);
+}
+
+// PTRACE_ATTACH shows up as a SIGSTOP signal-delivery-stop, PTRACE_INTERRUPT as a
+// PTRACE_EVENT_STOP. A different signal may get reported first, that one is handed
+// over to the target and we keep waiting for ours
+fn wait_for_attach_stop(pid: pid_t, mode: AttachMode) -> Result<()> {
+    loop {
+        // __WALL, non-leader threads are clone children as far as wait is concerned
+        match waitpid(Pid::from_raw(pid), Some(WaitPidFlag::__WALL)) {
+            Ok(WaitStatus::Stopped(_, Signal::SIGSTOP)) if mode == AttachMode::Attach => return Ok(()),
+            Ok(WaitStatus::PtraceEvent(_, _, PTRACE_EVENT_STOP)) if mode == AttachMode::Seize => return Ok(()),
+            Ok(WaitStatus::Stopped(_, sig)) => {
+                vlog!("[ptrace] {} got {:?} while attaching, passing it on", pid, sig);
+                if unsafe { libc::ptrace(libc::PTRACE_CONT, pid, ptr::null_mut::<c_void>(), sig as i32 as usize as *mut c_void) } < 0 {
+                    return Err(Error::last_os_error().into());
+                }
+            }
+            Ok(WaitStatus::Exited(_, code)) => return Err(InjectError::ProcessExited { pid, code: Some(code), signal: None }),
+            Ok(WaitStatus::Signaled(_, sig, _)) => return Err(InjectError::ProcessExited { pid, code: None, signal: Some(sig) }),
+            Ok(status) => {
+                return Err(Error::other(format!("unexpected stop while attaching to {}: {:?}", pid, status)).into());
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Attach every thread of `pid` not in `attached` yet, returning the ones we stopped.
+/// Running threads can spawn more while we go, so /proc/<pid>/task is walked again
+/// until a pass turns up nothing new
+pub fn ptrace_attach_threads(pid: pid_t, attached: &[pid_t], mode: AttachMode, options: i32) -> Result<Vec<pid_t>> {
+    let mut seen: Vec<pid_t> = attached.to_vec();
+    let mut stopped = Vec::new();
+
+    loop {
+        let new: Vec<pid_t> = get_thread_ids(pid).into_iter().filter(|tid| !seen.contains(tid)).collect();
+        if new.is_empty() {
+            break;
+        }
+
+        for tid in new {
+            seen.push(tid);
+            match ptrace_attach(tid, mode, options) {
+                Ok(()) => stopped.push(tid),
+                // exited between listing and attaching, nothing to stop
+                Err(InjectError::ProcessNotFound(_)) => continue,
+                Err(e) => {
+                    for tid in stopped {
+                        let _ = ptrace_detach(tid);
+                    }
+                    return Err(e);
+                }
+            }
         }
-        ptrace_poke_word(pid, tail_addr, word)?;
     }
 
     #[cfg(debug_assertions)]
-    eprintln!("[ptrace] Wrote {} bytes to {:p}", data.len(), addr);
+    eprintln!("[ptrace] Stopped {} more thread(s) of {}", stopped.len(), pid);
+    Ok(stopped)
+}
+
+pub fn ptrace_detach(pid: pid_t) -> Result<()> {
+    if unsafe { libc::ptrace(libc::PTRACE_DETACH, pid, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) } < 0 {
+        return Err(Error::last_os_error().into());
+    }
+    #[cfg(debug_assertions)]
+    eprintln!("[ptrace] Detached from {}", pid);
     Ok(())
 }
 
-fn wait_until_stopped(pid: pid_t) -> Result<WaitStatus> {
-    println!("[ptrace] wait_until_stopped: waiting for pid {}", pid);
+/// Read one word at `addr`. PEEKDATA returns the data itself so -1 is ambiguous,
+/// errno has to be cleared up front and checked afterwards
+pub fn ptrace_peek_word(pid: pid_t, addr: u64) -> std::io::Result<usize> {
+    Errno::clear();
+    let word = unsafe { libc::ptrace(libc::PTRACE_PEEKDATA, pid, addr as usize as *mut c_void, ptr::null_mut::<c_void>()) };
+    if word == -1 && Errno::last_raw() != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(word as usize)
+}
+
+pub fn ptrace_poke_word(pid: pid_t, addr: u64, data: usize) -> std::io::Result<()> {
+    let ret = unsafe { libc::ptrace(libc::PTRACE_POKEDATA, pid, addr as usize as *mut c_void, data) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Wait for the next signal stop, None once `deadline` passes without one. With a
+// deadline waitpid is polled, backing off from 1ms to 10ms between polls
+fn wait_until_stopped(pid: pid_t, deadline: Option<Instant>) -> Result<Option<Signal>> {
+    #[cfg(debug_assertions)]
+    eprintln!("[ptrace] wait_until_stopped: waiting for pid {}", pid);
+    let flags = match deadline {
+        Some(_) => WaitPidFlag::__WALL | WaitPidFlag::WNOHANG,
+        None => WaitPidFlag::__WALL,
+    };
+    let mut nap = Duration::from_millis(1);
     loop {
-        match waitpid(Pid::from_raw(pid), None) {
-            Ok(WaitStatus::Stopped(_, _sig)) => {
-                println!("[ptrace] wait_until_stopped: process stopped with signal {:?}", _sig);
-                return Ok(WaitStatus::Stopped(Pid::from_raw(pid), _sig));
+        match waitpid(Pid::from_raw(pid), Some(flags)) {
+            Ok(WaitStatus::StillAlive) => {
+                if deadline.is_some_and(|d| Instant::now() >= d) {
+                    #[cfg(debug_assertions)]
+                    eprintln!("[ptrace] wait_until_stopped: timed out");
+                    return Ok(None);
+                }
+                thread::sleep(nap);
+                nap = (nap * 2).min(Duration::from_millis(10));
+            }
+            Ok(WaitStatus::Stopped(_, sig)) => {
+                #[cfg(debug_assertions)]
+                eprintln!("[ptrace] wait_until_stopped: process stopped with signal {:?}", sig);
+                return Ok(Some(sig));
             }
             Ok(WaitStatus::Exited(_, code)) => {
-                println!("[ptrace] wait_until_stopped: process exited with code {}", code);
-                return Err(Error::new(std::io::ErrorKind::Other, format!("tracee exited with {}", code)));
+                #[cfg(debug_assertions)]
+                eprintln!("[ptrace] wait_until_stopped: process exited with code {}", code);
+                return Err(InjectError::ProcessExited { pid, code: Some(code), signal: None });
             }
             Ok(WaitStatus::Signaled(_, sig, _core)) => {
-                println!("[ptrace] wait_until_stopped: process signaled with {:?}", sig);
-                return Err(Error::new(std::io::ErrorKind::Other, format!("tracee signaled: {:?}", sig)));
+                #[cfg(debug_assertions)]
+                eprintln!("[ptrace] wait_until_stopped: process signaled with {:?}", sig);
+                return Err(InjectError::ProcessExited { pid, code: None, signal: Some(sig) });
             }
-            Ok(status) => {
-                println!("[ptrace] wait_until_stopped: other status: {:?}, continuing to wait", status);
+            Ok(WaitStatus::PtraceEvent(_, _sig, PTRACE_EVENT_STOP)) => {
+                // A seized tracee reports job control stops as event stops, those are not
+                // the end of our call, resume it and keep waiting
+                #[cfg(debug_assertions)]
+                eprintln!("[ptrace] wait_until_stopped: group-stop ({:?}), resuming", _sig);
+                if unsafe { libc::ptrace(libc::PTRACE_CONT, pid, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) } < 0 {
+                    return Err(Error::last_os_error().into());
+                }
+            }
+            Ok(_status) => {
+                #[cfg(debug_assertions)]
+                eprintln!("[ptrace] wait_until_stopped: other status: {:?}, continuing to wait", _status);
                 continue;
             }
             Err(e) => {
-                println!("[ptrace] wait_until_stopped: error waiting for process: {:?}", e);
-                return Err(Error::from(e));
+                #[cfg(debug_assertions)]
+                eprintln!("[ptrace] wait_until_stopped: error waiting for process: {:?}", e);
+                return Err(e.into());
             }
         }
     }
 }
 
+/// The PTRACE_GETEVENTMSG value of a PTRACE_EVENT_* stop, the new pid for a fork
+pub(crate) fn ptrace_event_msg(pid: pid_t) -> Result<libc::c_ulong> {
+    let mut msg: libc::c_ulong = 0;
+    if unsafe { libc::ptrace(libc::PTRACE_GETEVENTMSG, pid, ptr::null_mut::<c_void>(), &mut msg as *mut _ as *mut c_void) } < 0 {
+        return Err(Error::last_os_error().into());
+    }
+    Ok(msg)
+}
+
+pub(crate) fn ptrace_cont(pid: pid_t, sig: Option<Signal>) -> Result<()> {
+    let data = sig.map(|s| s as i32).unwrap_or(0) as usize;
+    if unsafe { libc::ptrace(libc::PTRACE_CONT, pid, ptr::null_mut::<c_void>(), data as *mut c_void) } < 0 {
+        return Err(Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// How a remote call ended
+pub(crate) enum Trap {
+    /// Came back through our return trap
+    Returned,
+    /// Faulted somewhere else, the called code crashed
+    Crashed { signal: Signal, pc: u64 },
+    /// Still running when the call timeout ran out, stopped at `pc`
+    TimedOut { pc: u64, timeout: Duration },
+}
+
+// How much code around a crashing pc ends up in the report
+const CRASH_CODE_BEFORE: u64 = 16;
+const CRASH_CODE_LEN: usize = 48;
+
+impl Trap {
+    /// Turn a crash into `RemoteCallCrashed`, `registers` names the state the
+    /// thread crashed with and is only evaluated for an actual crash
+    fn check(self, pid: pid_t, registers: impl FnOnce() -> Vec<(&'static str, u64)>) -> Result<()> {
+        let (signal, pc) = match self {
+            Trap::Returned => return Ok(()),
+            Trap::Crashed { signal, pc } => (signal, pc),
+            Trap::TimedOut { pc, timeout } => return Err(InjectError::RemoteCallTimedOut { pc, timeout }),
+        };
+
+        // The bytes before pc may sit on an unmapped page, fall back to pc onwards
+        let memory = RemoteMemory::new(pid);
+        let before = pc.saturating_sub(CRASH_CODE_BEFORE);
+        let (code_addr, code) = match memory.read(before, CRASH_CODE_LEN) {
+            Ok(code) => (before, code),
+            Err(_) => (pc, memory.read(pc, CRASH_CODE_LEN - CRASH_CODE_BEFORE as usize).unwrap_or_default()),
+        };
+
+        Err(InjectError::RemoteCallCrashed(Box::new(CrashReport { signal, pc, registers: registers(), code_addr, code })))
+    }
+}
+
+// si_code <= 0 means the signal was sent by someone (kill, tgkill, sigqueue) rather
+// than raised by the kernel for something the thread itself did
+fn is_user_sent(pid: pid_t) -> bool {
+    let mut info: libc::siginfo_t = unsafe { zeroed() };
+    let ret = unsafe { libc::ptrace(libc::PTRACE_GETSIGINFO, pid, ptr::null_mut::<c_void>(), &mut info as *mut _ as *mut c_void) };
+    ret == 0 && info.si_code <= 0
+}
+
+/// Let the remote call run until it comes back through our trap: `trap_signal` with
+/// the pc at `trap_pc` (SIGSEGV at 0 for the return address trick, SIGTRAP on the stub's
+/// breakpoint). A fault anywhere else is a crash in the called code. Every other signal
+/// belongs to the target (timers, SIGCHLD, ...), it is held back on the tracee to be
+/// re-sent on detach and the call carries on
+pub(crate) fn wait_for_trap(tracee: &mut Tracee, trap_signal: Signal, trap_pc: u64, read_pc: fn(pid_t) -> Result<u64>) -> Result<Trap> {
+    let pid = tracee.pid;
+    let deadline = tracee.call_timeout.map(|t| Instant::now() + t);
+    loop {
+        let Some(sig) = wait_until_stopped(pid, deadline)? else {
+            interrupt_call(tracee)?;
+            let pc = read_pc(pid)?;
+            let timeout = tracee.call_timeout.unwrap_or_default();
+            eprintln!("[ptrace] remote call on {} still running after {:?}, interrupted at pc 0x{:x}", pid, timeout, pc);
+            return Ok(Trap::TimedOut { pc, timeout });
+        };
+        let pc = read_pc(pid)?;
+        if sig == trap_signal && pc == trap_pc {
+            return Ok(Trap::Returned);
+        }
+
+        let fault = matches!(
+            sig,
+            Signal::SIGSEGV | Signal::SIGBUS | Signal::SIGILL | Signal::SIGFPE | Signal::SIGTRAP | Signal::SIGSYS
+        );
+        if sig == Signal::SIGABRT || (fault && !is_user_sent(pid)) {
+            return Ok(Trap::Crashed { signal: sig, pc });
+        }
+
+        vlog!("[ptrace] {} got {:?} at pc 0x{:x} during a remote call, holding it back", pid, sig, pc);
+        tracee.pending_signals.push(sig);
+        ptrace_cont(pid, None)?;
+    }
+}
+
+// Let the tracee run until it executes `breakpoint`, planted at `addr` for the ride and
+// taken out again whatever happened. The breakpoint traps with the pc at `trap_pc`
+fn run_to_breakpoint(tracee: &mut Tracee, addr: u64, breakpoint: &[u8], trap_pc: u64, read_pc: fn(pid_t) -> Result<u64>) -> Result<Trap> {
+    let memory = RemoteMemory::new(tracee.pid);
+    let original = memory.read(addr, breakpoint.len())?;
+    memory.write(addr, breakpoint)?;
+
+    let trap = ptrace_cont(tracee.pid, None).and_then(|_| wait_for_trap(tracee, Signal::SIGTRAP, trap_pc, read_pc));
+    memory.write(addr, &original)?;
+    trap
+}
+
+// Stop a remote call that overran its timeout: PTRACE_INTERRUPT when seized, a
+// thread directed SIGSTOP (suppressed again once it is reported) otherwise. Any
+// other signal reported first is held back like during the call
+fn interrupt_call(tracee: &mut Tracee) -> Result<()> {
+    let pid = tracee.pid;
+    if tracee.seized {
+        ptrace_interrupt(pid)?;
+    } else if unsafe { libc::syscall(libc::SYS_tkill, pid, libc::SIGSTOP) } < 0 {
+        return Err(Error::last_os_error().into());
+    }
+
+    loop {
+        match waitpid(Pid::from_raw(pid), Some(WaitPidFlag::__WALL)) {
+            Ok(WaitStatus::Stopped(_, Signal::SIGSTOP)) if !tracee.seized => return Ok(()),
+            Ok(WaitStatus::PtraceEvent(_, _, PTRACE_EVENT_STOP)) if tracee.seized => return Ok(()),
+            Ok(WaitStatus::Stopped(_, sig)) => {
+                tracee.pending_signals.push(sig);
+                ptrace_cont(pid, None)?;
+            }
+            Ok(WaitStatus::Exited(_, code)) => return Err(InjectError::ProcessExited { pid, code: Some(code), signal: None }),
+            Ok(WaitStatus::Signaled(_, sig, _)) => return Err(InjectError::ProcessExited { pid, code: None, signal: Some(sig) }),
+            Ok(_) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Send signals held back during remote calls to thread `tid` of `pid`. Meant for after
+/// detaching, so they are delivered the normal way. The original siginfo is lost
+pub fn redeliver_signals(pid: pid_t, tid: pid_t, signals: &[Signal]) {
+    for sig in signals {
+        let ret = unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, *sig as i32) };
+        if ret < 0 {
+            vlog!("[ptrace] re-sending {:?} to {} failed: {}", sig, tid, Error::last_os_error());
+        } else {
+            vlog!("[ptrace] re-sent {:?} to {}", sig, tid);
+        }
+    }
+}
+
 // aarch64 mode only
 #[cfg(target_arch = "aarch64")]
 mod arch64 {
@@ -124,17 +553,25 @@ mod arch64 {
     pub fn get_regs(pid: pid_t, regs: &mut PtRegs) -> Result<()> {
         let mut iov = iovec { iov_base: regs as *mut _ as *mut c_void, iov_len: std::mem::size_of::<PtRegs>() };
         let ret = unsafe { libc::ptrace(libc::PTRACE_GETREGSET, pid, NT_PRSTATUS as *mut c_void, &mut iov as *mut _ as *mut c_void) };
-        if ret < 0 { return Err(Error::last_os_error()); }
+        if ret < 0 { return Err(Error::last_os_error().into()); }
         Ok(())
     }
 
     pub fn set_regs(pid: pid_t, regs: &PtRegs) -> Result<()> {
         let mut iov = iovec { iov_base: regs as *const _ as *mut c_void, iov_len: std::mem::size_of::<PtRegs>() };
         let ret = unsafe { libc::ptrace(libc::PTRACE_SETREGSET, pid, NT_PRSTATUS as *mut c_void, &mut iov as *mut _ as *mut c_void) };
-        if ret < 0 { return Err(Error::last_os_error()); }
+        if ret < 0 { return Err(Error::last_os_error().into()); }
         Ok(())
     }
 
+    // Interrupted syscalls: unlike x86, the kernel deals with the restart before the
+    // signal stop we attach through. pc is already rewound onto the `svc`, x0 reset to
+    // orig_x0 and the syscall forgotten (NT_ARM_SYSTEM_CALL reads -1), so restoring the
+    // saved registers simply re-issues the original call. That is right for everything
+    // restarted with -ERESTARTSYS/-ERESTARTNOHAND, but the -ERESTART_RESTARTBLOCK case
+    // (relative nanosleep/clock_nanosleep, futex waits with a timeout) should resume
+    // through restart_syscall, and that return value is gone by the time we see the
+    // thread. Such a wait starts over with its full timeout after an injection
     #[inline]
     fn a64_call_stub() -> [u8; 8] {
         // blr x17 ; brk #0
@@ -157,12 +594,36 @@ mod arch64 {
         b
     }
 
-    // Simple page for tramp
-    static mut STUB_PAGE: u64 = 0;
+    fn read_pc(pid: pid_t) -> Result<u64> {
+        let mut regs: PtRegs = unsafe { zeroed() };
+        get_regs(pid, &mut regs)?;
+        Ok(regs.pc)
+    }
+
+    const REG_NAMES: [&str; 31] = [
+        "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13", "x14", "x15",
+        "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26", "x27", "x28", "x29", "x30",
+    ];
+
+    fn named_regs(regs: &PtRegs) -> Vec<(&'static str, u64)> {
+        let mut named: Vec<_> = REG_NAMES.iter().copied().zip(regs.regs).collect();
+        named.extend([("sp", regs.sp), ("pc", regs.pc), ("pstate", regs.pstate)]);
+        named
+    }
+
+    // brk leaves pc on itself, once the original instruction is back we are at `entry`
+    pub fn run_to_entry(tracee: &mut Tracee, entry: u64) -> Result<()> {
+        let brk0: u32 = 0xD420_0000;
+        let trap = super::run_to_breakpoint(tracee, entry, &brk0.to_le_bytes(), entry, read_pc)?;
+        let mut regs: PtRegs = unsafe { zeroed() };
+        get_regs(tracee.pid, &mut regs)?;
+        trap.check(tracee.pid, || named_regs(&regs))
+    }
 
     // Set LR (x30) = 0 so when the callee RETs it segfaults at 0 so we can catch halt
     // Read x0, restore regs then continue
-    fn call_lib_once_with_lr_trap(pid: pid_t, func_addr: u64, args: &[u64]) -> Result<u64> {
+    fn call_lib_once_with_lr_trap(tracee: &mut Tracee, func_addr: u64, args: &[u64]) -> Result<u64> {
+        let pid = tracee.pid;
         let mut regs: PtRegs = unsafe { zeroed() };
         get_regs(pid, &mut regs)?;
         let backup = regs;
@@ -178,24 +639,24 @@ mod arch64 {
 
         set_regs(pid, &regs)?;
         let r = unsafe { libc::ptrace(libc::PTRACE_CONT, pid, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) };
-        if r < 0 { set_regs(pid, &backup).ok(); return Err(Error::last_os_error()); }
+        if r < 0 { set_regs(pid, &backup).ok(); return Err(Error::last_os_error().into()); }
 
-        // Wait for SIGSEGV
-        super::wait_until_stopped(pid)?;
+        // Wait for SIGSEGV at pc 0
+        let trap = super::wait_for_trap(tracee, Signal::SIGSEGV, 0, read_pc)?;
         get_regs(pid, &mut regs)?;
         let ret = regs.regs[0];
 
         // Restore regs so we don't re-execute anything
         set_regs(pid, &backup)?;
+        trap.check(pid, || named_regs(&regs))?;
         Ok(ret)
     }
 
     // Ensure we have a trampoline page; if not, get one
     // We resolve and call libc::mmap once using the LR-trap trick, then place our stubs there
-    fn ensure_stub_page(pid: pid_t, libc_mmap_addr: u64) -> Result<u64> {
-        unsafe {
-            if STUB_PAGE != 0 { return Ok(STUB_PAGE); }
-        }
+    fn ensure_stub_page(tracee: &mut Tracee, libc_mmap_addr: u64) -> Result<u64> {
+        if tracee.stub_page != 0 { return Ok(tracee.stub_page); }
+        let pid = tracee.pid;
 
         // mmap(NULL, 0x1000, PROT_RWX, MAP_PRIVATE|MAP_ANON, -1, 0) via libc mmap
         let page_sz = 0x1000u64;
@@ -204,21 +665,31 @@ mod arch64 {
         let fd      = !0u64;
         let off     = 0u64;
 
-        let addr = call_lib_once_with_lr_trap(pid, libc_mmap_addr, &[0, page_sz, prot, flags, fd, off])?;
+        let addr = call_lib_once_with_lr_trap(tracee, libc_mmap_addr, &[0, page_sz, prot, flags, fd, off])?;
         if addr == 0 || (addr as i64) < 0 {
-            return Err(Error::new(std::io::ErrorKind::Other, format!("bootstrap mmap failed: 0x{:x}", addr)));
+            return Err(InjectError::RemoteAllocFailed { ret: addr });
         }
 
         // Write both stubs into the page: put call stub at +0, syscall stub at +8 (or vice versa).
         let call_stub = a64_call_stub();
-        super::ptrace_write(pid, addr as *mut u8, &call_stub)?;
+        RemoteMemory::new(pid).write(addr, &call_stub)?;
         let sysc_stub = a64_syscall_stub();
-        super::ptrace_write(pid, (addr + 8) as *mut u8, &sysc_stub)?;
+        RemoteMemory::new(pid).write(addr + 8, &sysc_stub)?;
 
-        unsafe { STUB_PAGE = addr; }
+        tracee.stub_page = addr;
         Ok(addr)
     }
 
+    // munmap the stub page, through the LR trap since the stub can't return into a page
+    // that no longer exists
+    pub fn release_stub_page(tracee: &mut Tracee, libc_munmap_addr: u64) -> Result<()> {
+        if tracee.stub_page == 0 { return Ok(()); }
+        let page = tracee.stub_page;
+        tracee.stub_page = 0;
+        call_lib_once_with_lr_trap(tracee, libc_munmap_addr, &[page, 0x1000])?;
+        Ok(())
+    }
+
     pub(super) fn spill_extra_args_aapcs64(pid: pid_t, regs: &mut PtRegs, args: &[u64]) -> Result<()> {
         if args.len() <= 8 { return Ok(()); }
         let extra = &args[8..];
@@ -230,16 +701,17 @@ mod arch64 {
         for a in extra { raw.extend_from_slice(&a.to_ne_bytes()); }
         while raw.len() % 16 != 0 { raw.push(0); }
 
-        super::ptrace_write(pid, new_sp as *mut u8, &raw)?;
+        RemoteMemory::new(pid).write(new_sp, &raw)?;
         regs.sp = new_sp;
         Ok(())
     }
 
     // Uses the call-stub if available; on first use it bootstraps the stub page by
     // call libc::mmap once via LR-trap. Pass the remote address of mmap as `libc_mmap_addr`
-    pub fn call_remote_function(pid: pid_t, func_addr: u64, args: &[u64], libc_mmap_addr: u64) -> Result<u64> {
+    pub fn call_remote_function(tracee: &mut Tracee, func_addr: u64, args: &[u64], libc_mmap_addr: u64) -> Result<u64> {
         // Ensure we have a stub page; if not, this will use LR-trap to get one via libc::mmap
-        let page = ensure_stub_page(pid, libc_mmap_addr)?;
+        let page = ensure_stub_page(tracee, libc_mmap_addr)?;
+        let pid = tracee.pid;
         let call = page; // call stub sits at +0
 
         // Normal call via `blr x17; brk #0`
@@ -258,55 +730,122 @@ mod arch64 {
         let r = unsafe { libc::ptrace(libc::PTRACE_CONT, pid, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) };
         if r < 0 {
             set_regs(pid, &backup).ok();
-            return Err(Error::last_os_error());
+            return Err(Error::last_os_error().into());
         }
 
-        super::wait_until_stopped(pid)?;
+        // brk #0 right after the blr
+        let trap = super::wait_for_trap(tracee, Signal::SIGTRAP, call + 4, read_pc)?;
         get_regs(pid, &mut regs)?;
         let ret = regs.regs[0];
 
         set_regs(pid, &backup)?;
+        trap.check(pid, || named_regs(&regs))?;
         Ok(ret)
     }
 }
 
+// 32-bit targets get the arm32 backend, driven through the compat register view
+#[cfg(target_arch = "aarch64")]
+pub fn call_remote_function(tracee: &mut Tracee, func_addr: u64, args: &[u64], libc_mmap_addr: u64) -> Result<u64> {
+    if tracee.compat {
+        arch32::call_remote_function(tracee, func_addr, args, libc_mmap_addr)
+    } else {
+        arch64::call_remote_function(tracee, func_addr, args, libc_mmap_addr)
+    }
+}
+
 #[cfg(target_arch = "aarch64")]
-pub use arch64::call_remote_function;
+pub fn run_to_entry(tracee: &mut Tracee, entry: u64) -> Result<()> {
+    if tracee.compat {
+        arch32::run_to_entry(tracee, entry)
+    } else {
+        arch64::run_to_entry(tracee, entry)
+    }
+}
 
-// armv7/32bit
-#[cfg(target_arch = "arm")]
+/// Unmap the page holding the call stubs, if one was set up. Part of rolling back
+/// after a crash, the next remote call sets up a fresh one
+#[cfg(target_arch = "aarch64")]
+pub fn release_stub_page(tracee: &mut Tracee, libc_munmap_addr: u64) -> Result<()> {
+    if tracee.compat {
+        Ok(())
+    } else {
+        arch64::release_stub_page(tracee, libc_munmap_addr)
+    }
+}
+
+// armv7/32bit, natively or as a compat task under aarch64
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 mod arch32 {
     use super::*;
+    use libc::iovec;
 
-    fn get_regs(pid: pid_t, regs: &mut PtRegs) -> Result<()> {
-        let ret = unsafe {
-            libc::ptrace(libc::PTRACE_GETREGS, pid, ptr::null_mut::<c_void>(), regs as *mut _ as *mut c_void)
-        };
-        if ret < 0 { return Err(Error::last_os_error()); }
+    const NT_PRSTATUS: i32 = 1;
+
+    // GETREGS would hand an aarch64 tracer the 64-bit layout, GETREGSET gives us
+    // whatever the tracee itself uses, so it works the same from both injectors
+    fn get_regs(pid: pid_t, regs: &mut ArmRegs) -> Result<()> {
+        let mut iov = iovec { iov_base: regs as *mut _ as *mut c_void, iov_len: std::mem::size_of::<ArmRegs>() };
+        let ret = unsafe { libc::ptrace(libc::PTRACE_GETREGSET, pid, NT_PRSTATUS as *mut c_void, &mut iov as *mut _ as *mut c_void) };
+        if ret < 0 { return Err(Error::last_os_error().into()); }
         Ok(())
     }
 
-    fn set_regs(pid: pid_t, regs: &PtRegs) -> Result<()> {
-        let ret = unsafe {
-            libc::ptrace(libc::PTRACE_SETREGS, pid, ptr::null_mut::<c_void>(), regs as *const _ as *const c_void)
-        };
-        if ret < 0 { return Err(Error::last_os_error()); }
+    fn set_regs(pid: pid_t, regs: &ArmRegs) -> Result<()> {
+        let mut iov = iovec { iov_base: regs as *const _ as *mut c_void, iov_len: std::mem::size_of::<ArmRegs>() };
+        let ret = unsafe { libc::ptrace(libc::PTRACE_SETREGSET, pid, NT_PRSTATUS as *mut c_void, &mut iov as *mut _ as *mut c_void) };
+        if ret < 0 { return Err(Error::last_os_error().into()); }
         Ok(())
     }
 
-    pub fn call_remote_function(pid: pid_t, func_addr: u64, args: &[u64], _libc_mmap_addr: u64) -> Result<u64> {
-        let mut regs: PtRegs = unsafe { zeroed() };
+    fn read_pc(pid: pid_t) -> Result<u64> {
+        let mut regs: ArmRegs = unsafe { zeroed() };
+        get_regs(pid, &mut regs)?;
+        Ok(regs.uregs[15] as u64)
+    }
+
+    const REG_NAMES: [&str; 18] = [
+        "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "fp", "ip", "sp", "lr", "pc", "cpsr", "orig_r0",
+    ];
+
+    fn named_regs(regs: &ArmRegs) -> Vec<(&'static str, u64)> {
+        REG_NAMES.iter().copied().zip(regs.uregs.map(u64::from)).collect()
+    }
+
+    // The undefined instructions the kernel reserves for ptrace breakpoints, a Thumb
+    // entry point (bit 0 set) needs the 16-bit one. pc stays on the breakpoint
+    pub fn run_to_entry(tracee: &mut Tracee, entry: u64) -> Result<()> {
+        let addr = entry & !1;
+        let thumb_bkpt: u16 = 0xDE01;
+        let arm_bkpt: u32 = 0xE7F0_01F0;
+        let trap = if (entry & 1) != 0 {
+            super::run_to_breakpoint(tracee, addr, &thumb_bkpt.to_le_bytes(), addr, read_pc)?
+        } else {
+            super::run_to_breakpoint(tracee, addr, &arm_bkpt.to_le_bytes(), addr, read_pc)?
+        };
+        let mut regs: ArmRegs = unsafe { zeroed() };
+        get_regs(tracee.pid, &mut regs)?;
+        trap.check(tracee.pid, || named_regs(&regs))
+    }
+
+    // Same as arch64: the kernel rewinds pc onto the `svc` and resets r0 before the signal
+    // stop, so restoring the saved registers re-issues an interrupted syscall. Relative
+    // sleeps and timed futex waits (-ERESTART_RESTARTBLOCK) start over with their full
+    // timeout instead of going through restart_syscall
+    pub fn call_remote_function(tracee: &mut Tracee, func_addr: u64, args: &[u64], _libc_mmap_addr: u64) -> Result<u64> {
+        let pid = tracee.pid;
+        let mut regs: ArmRegs = unsafe { zeroed() };
         get_regs(pid, &mut regs)?;
         let backup = regs;
 
-        for i in 0..args.len().min(4) { regs.uregs[i] = args[i] as u32; }
+        for (i, a) in args.iter().take(4).enumerate() { regs.uregs[i] = *a as u32; }
 
         if args.len() > 4 {
             let extra = &args[4..];
             let sp = (regs.uregs[13] as usize).wrapping_sub(extra.len() * size_of::<u32>());
             let mut raw = Vec::with_capacity(extra.len() * 4);
             for a in extra { raw.extend_from_slice(&(*a as u32).to_ne_bytes()); }
-            super::ptrace_write(pid, sp as *mut u8, &raw)?;
+            RemoteMemory::new(pid).write(sp as u64, &raw)?;
             regs.uregs[13] = sp as u32;
         }
 
@@ -317,14 +856,16 @@ mod arch32 {
 
         set_regs(pid, &regs)?;
         let r = unsafe { libc::ptrace(libc::PTRACE_CONT, pid, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) };
-        if r < 0 { set_regs(pid, &backup).ok(); return Err(Error::last_os_error()); }
+        if r < 0 { set_regs(pid, &backup).ok(); return Err(Error::last_os_error().into()); }
 
-        let _ = super::wait_until_stopped(pid)?;
+        // Wait for SIGSEGV at pc 0
+        let trap = super::wait_for_trap(tracee, Signal::SIGSEGV, 0, read_pc)?;
 
         get_regs(pid, &mut regs)?;
         let ret = regs.uregs[0] as u64;
 
         set_regs(pid, &backup)?;
+        trap.check(pid, || named_regs(&regs))?;
 
     #[cfg(debug_assertions)]
         eprintln!("[ptrace:arm] call_remote_function 0x{func_addr:x} -> 0x{ret:x}");
@@ -334,4 +875,338 @@ mod arch32 {
 }
 
 #[cfg(target_arch = "arm")]
-pub use arch32::call_remote_function;
\ No newline at end of file
+pub use arch32::{call_remote_function, run_to_entry};
+
+// No stub page on arm, the LR trap is used for every call
+#[cfg(target_arch = "arm")]
+pub fn release_stub_page(_tracee: &mut Tracee, _libc_munmap_addr: u64) -> Result<()> {
+    Ok(())
+}
+// x86_64 (desktop Linux, Android emulator images)
+#[cfg(target_arch = "x86_64")]
+mod archx86_64 {
+    use super::*;
+
+    // System V keeps 128 bytes below rsp for leaf functions, never touch it
+    const RED_ZONE: u64 = 128;
+
+    pub fn get_regs(pid: pid_t, regs: &mut PtRegs) -> Result<()> {
+        let ret = unsafe {
+            libc::ptrace(libc::PTRACE_GETREGS, pid, ptr::null_mut::<c_void>(), regs as *mut _ as *mut c_void)
+        };
+        if ret < 0 { return Err(Error::last_os_error().into()); }
+        Ok(())
+    }
+
+    pub fn set_regs(pid: pid_t, regs: &PtRegs) -> Result<()> {
+        let ret = unsafe {
+            libc::ptrace(libc::PTRACE_SETREGS, pid, ptr::null_mut::<c_void>(), regs as *const _ as *const c_void)
+        };
+        if ret < 0 { return Err(Error::last_os_error().into()); }
+        Ok(())
+    }
+
+    #[inline]
+    fn x64_call_stub() -> [u8; 8] {
+        // call *%r11 ; int3 ; padded with int3
+        [0x41, 0xFF, 0xD3, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]
+    }
+
+    #[inline]
+    fn x64_syscall_stub() -> [u8; 8] {
+        // syscall ; int3 ; padded with int3
+        [0x0F, 0x05, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]
+    }
+
+    // Interrupted syscalls are restarted by the kernel when the thread resumes, based on
+    // orig_rax (syscall number) and rax (-ERESTART* code). With our registers in place
+    // that would rewind rip into the middle of the called function, so orig_rax = -1
+    // for the call. The backup keeps both, restoring it re-arms the pending restart
+    fn forget_syscall(pid: pid_t, regs: &mut PtRegs) {
+        if (regs.orig_rax as i64) >= 0 && (-516..=-512).contains(&(regs.rax as i64)) {
+            vlog!("[ptrace:x86_64] {} stopped in syscall {} ({}), restart state preserved", pid, regs.orig_rax, regs.rax as i64);
+        }
+        regs.orig_rax = u64::MAX;
+    }
+
+    const ARG_REGS: usize = 6;
+
+    fn load_arg_regs(regs: &mut PtRegs, args: &[u64]) {
+        let slots: [&mut u64; ARG_REGS] = [
+            &mut regs.rdi, &mut regs.rsi, &mut regs.rdx,
+            &mut regs.rcx, &mut regs.r8, &mut regs.r9,
+        ];
+        for (slot, a) in slots.into_iter().zip(args.iter()) { *slot = *a; }
+    }
+
+    // Lays out the stack the way the callee expects it right after a `call`:
+    // extra args at [rsp+8..] and 16-byte alignment of rsp+8 at entry. When
+    // `ret_addr` is given it is pushed as the return address, otherwise the
+    // stub's own `call` pushes it
+    pub(super) fn spill_extra_args_sysv(pid: pid_t, regs: &mut PtRegs, args: &[u64], ret_addr: Option<u64>) -> Result<()> {
+        let extra = if args.len() > ARG_REGS { &args[ARG_REGS..] } else { &[] };
+
+        let mut sp = regs.rsp.wrapping_sub(RED_ZONE);
+        sp = sp.wrapping_sub((extra.len() * 8) as u64) & !15;
+
+        if !extra.is_empty() {
+            let mut raw = Vec::with_capacity(extra.len() * 8);
+            for a in extra { raw.extend_from_slice(&a.to_ne_bytes()); }
+            RemoteMemory::new(pid).write(sp, &raw)?;
+        }
+
+        if let Some(ret) = ret_addr {
+            sp -= 8;
+            RemoteMemory::new(pid).write(sp, &ret.to_ne_bytes())?;
+        }
+
+        regs.rsp = sp;
+        Ok(())
+    }
+
+    fn read_pc(pid: pid_t) -> Result<u64> {
+        let mut regs: PtRegs = unsafe { zeroed() };
+        get_regs(pid, &mut regs)?;
+        Ok(regs.rip)
+    }
+
+    fn named_regs(regs: &PtRegs) -> Vec<(&'static str, u64)> {
+        vec![
+            ("rax", regs.rax), ("rbx", regs.rbx), ("rcx", regs.rcx), ("rdx", regs.rdx),
+            ("rsi", regs.rsi), ("rdi", regs.rdi), ("rbp", regs.rbp), ("rsp", regs.rsp),
+            ("r8", regs.r8), ("r9", regs.r9), ("r10", regs.r10), ("r11", regs.r11),
+            ("r12", regs.r12), ("r13", regs.r13), ("r14", regs.r14), ("r15", regs.r15),
+            ("rip", regs.rip), ("eflags", regs.eflags), ("orig_rax", regs.orig_rax),
+        ]
+    }
+
+    // int3 traps with rip past itself, rewound onto `entry` once the byte is restored
+    pub fn run_to_entry(tracee: &mut Tracee, entry: u64) -> Result<()> {
+        let trap = super::run_to_breakpoint(tracee, entry, &[0xCC], entry + 1, read_pc)?;
+        let mut regs: PtRegs = unsafe { zeroed() };
+        get_regs(tracee.pid, &mut regs)?;
+        trap.check(tracee.pid, || named_regs(&regs))?;
+        regs.rip = entry;
+        set_regs(tracee.pid, &regs)
+    }
+
+    // Push a return address of 0 so when the callee RETs it segfaults at 0 so we can catch halt
+    // Read rax, restore regs then continue
+    fn call_lib_once_with_ret_trap(tracee: &mut Tracee, func_addr: u64, args: &[u64]) -> Result<u64> {
+        let pid = tracee.pid;
+        let mut regs: PtRegs = unsafe { zeroed() };
+        get_regs(pid, &mut regs)?;
+        let backup = regs;
+        forget_syscall(pid, &mut regs);
+
+        load_arg_regs(&mut regs, args);
+        spill_extra_args_sysv(pid, &mut regs, args, Some(0))?;
+
+        // al = number of vector registers used by varargs
+        regs.rax = 0;
+        regs.rip = func_addr;
+
+        set_regs(pid, &regs)?;
+        let r = unsafe { libc::ptrace(libc::PTRACE_CONT, pid, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) };
+        if r < 0 { set_regs(pid, &backup).ok(); return Err(Error::last_os_error().into()); }
+
+        // Wait for SIGSEGV at rip 0
+        let trap = super::wait_for_trap(tracee, Signal::SIGSEGV, 0, read_pc)?;
+        get_regs(pid, &mut regs)?;
+        let ret = regs.rax;
+
+        // Restore regs so we don't re-execute anything
+        set_regs(pid, &backup)?;
+        trap.check(pid, || named_regs(&regs))?;
+        Ok(ret)
+    }
+
+    // Ensure we have a trampoline page; if not, get one
+    // We resolve and call libc::mmap once using the return-address trap, then place our stubs there
+    fn ensure_stub_page(tracee: &mut Tracee, libc_mmap_addr: u64) -> Result<u64> {
+        if tracee.stub_page != 0 { return Ok(tracee.stub_page); }
+        let pid = tracee.pid;
+
+        // mmap(NULL, 0x1000, PROT_RWX, MAP_PRIVATE|MAP_ANON, -1, 0) via libc mmap
+        let page_sz = 0x1000u64;
+        let prot    = (libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC) as u64;
+        let flags   = (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u64;
+        let fd      = !0u64;
+        let off     = 0u64;
+
+        let addr = call_lib_once_with_ret_trap(tracee, libc_mmap_addr, &[0, page_sz, prot, flags, fd, off])?;
+        if addr == 0 || (addr as i64) < 0 {
+            return Err(InjectError::RemoteAllocFailed { ret: addr });
+        }
+
+        // call stub at +0, syscall stub at +8
+        RemoteMemory::new(pid).write(addr, &x64_call_stub())?;
+        RemoteMemory::new(pid).write(addr + 8, &x64_syscall_stub())?;
+
+        tracee.stub_page = addr;
+        Ok(addr)
+    }
+
+    // munmap the stub page, through the return-address trap since the stub can't return
+    // into a page that no longer exists
+    pub fn release_stub_page(tracee: &mut Tracee, libc_munmap_addr: u64) -> Result<()> {
+        if tracee.stub_page == 0 { return Ok(()); }
+        let page = tracee.stub_page;
+        tracee.stub_page = 0;
+        call_lib_once_with_ret_trap(tracee, libc_munmap_addr, &[page, 0x1000])?;
+        Ok(())
+    }
+
+    // Uses the call-stub if available; on first use it bootstraps the stub page by
+    // call libc::mmap once via the return-address trap. Pass the remote address of mmap as `libc_mmap_addr`
+    pub fn call_remote_function(tracee: &mut Tracee, func_addr: u64, args: &[u64], libc_mmap_addr: u64) -> Result<u64> {
+        let page = ensure_stub_page(tracee, libc_mmap_addr)?;
+        let pid = tracee.pid;
+        let call = page; // call stub sits at +0
+
+        // Normal call via `call *%r11; int3`
+        let mut regs: PtRegs = unsafe { zeroed() };
+        get_regs(pid, &mut regs)?;
+        let backup = regs;
+        forget_syscall(pid, &mut regs);
+
+        load_arg_regs(&mut regs, args);
+        spill_extra_args_sysv(pid, &mut regs, args, None)?;
+
+        regs.rax = 0;
+        regs.r11 = func_addr;
+        regs.rip = call;
+
+        set_regs(pid, &regs)?;
+        let r = unsafe { libc::ptrace(libc::PTRACE_CONT, pid, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) };
+        if r < 0 {
+            set_regs(pid, &backup).ok();
+            return Err(Error::last_os_error().into());
+        }
+
+        // int3 at +3 reports rip past itself
+        let trap = super::wait_for_trap(tracee, Signal::SIGTRAP, call + 4, read_pc)?;
+        get_regs(pid, &mut regs)?;
+        let ret = regs.rax;
+
+        set_regs(pid, &backup)?;
+        trap.check(pid, || named_regs(&regs))?;
+
+        #[cfg(debug_assertions)]
+        eprintln!("[ptrace:x86_64] call_remote_function 0x{func_addr:x} -> 0x{ret:x}");
+
+        Ok(ret)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+pub use archx86_64::{call_remote_function, release_stub_page, run_to_entry};
+
+// i686/x86 32bit
+#[cfg(target_arch = "x86")]
+mod archx86 {
+    use super::*;
+
+    fn get_regs(pid: pid_t, regs: &mut PtRegs) -> Result<()> {
+        let ret = unsafe {
+            libc::ptrace(libc::PTRACE_GETREGS, pid, ptr::null_mut::<c_void>(), regs as *mut _ as *mut c_void)
+        };
+        if ret < 0 { return Err(Error::last_os_error().into()); }
+        Ok(())
+    }
+
+    fn set_regs(pid: pid_t, regs: &PtRegs) -> Result<()> {
+        let ret = unsafe {
+            libc::ptrace(libc::PTRACE_SETREGS, pid, ptr::null_mut::<c_void>(), regs as *const _ as *const c_void)
+        };
+        if ret < 0 { return Err(Error::last_os_error().into()); }
+        Ok(())
+    }
+
+    fn read_pc(pid: pid_t) -> Result<u64> {
+        let mut regs: PtRegs = unsafe { zeroed() };
+        get_regs(pid, &mut regs)?;
+        Ok(regs.eip as u64)
+    }
+
+    fn named_regs(regs: &PtRegs) -> Vec<(&'static str, u64)> {
+        [
+            ("eax", regs.eax), ("ebx", regs.ebx), ("ecx", regs.ecx), ("edx", regs.edx),
+            ("esi", regs.esi), ("edi", regs.edi), ("ebp", regs.ebp), ("esp", regs.esp),
+            ("eip", regs.eip), ("eflags", regs.eflags), ("orig_eax", regs.orig_eax),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name, value as u64))
+        .collect()
+    }
+
+    // int3 traps with eip past itself, rewound onto `entry` once the byte is restored
+    pub fn run_to_entry(tracee: &mut Tracee, entry: u64) -> Result<()> {
+        let trap = super::run_to_breakpoint(tracee, entry, &[0xCC], entry + 1, read_pc)?;
+        let mut regs: PtRegs = unsafe { zeroed() };
+        get_regs(tracee.pid, &mut regs)?;
+        trap.check(tracee.pid, || named_regs(&regs))?;
+        regs.eip = entry as u32;
+        set_regs(tracee.pid, &regs)
+    }
+
+    // Same restart logic as on x86_64: orig_eax/eax of an interrupted syscall would make
+    // the kernel rewind eip and clobber eax once we resume into the called function.
+    // Both are neutralised for the call, the backup brings the pending restart back
+    fn forget_syscall(pid: pid_t, regs: &mut PtRegs) {
+        if (regs.orig_eax as i32) >= 0 && (-516..=-512).contains(&(regs.eax as i32)) {
+            vlog!("[ptrace:x86] {} stopped in syscall {} ({}), restart state preserved", pid, regs.orig_eax, regs.eax as i32);
+        }
+        regs.orig_eax = u32::MAX;
+        regs.eax = 0;
+    }
+
+    // cdecl: every argument goes on the stack, followed by a return address of 0 so
+    // when the callee RETs it segfaults at 0 so we can catch halt
+    pub fn call_remote_function(tracee: &mut Tracee, func_addr: u64, args: &[u64], _libc_mmap_addr: u64) -> Result<u64> {
+        let pid = tracee.pid;
+        let mut regs: PtRegs = unsafe { zeroed() };
+        get_regs(pid, &mut regs)?;
+        let backup = regs;
+        forget_syscall(pid, &mut regs);
+
+        // esp + 4 has to be 16 byte aligned at function entry
+        let args_len = (args.len() * size_of::<u32>()) as u32;
+        let sp = (regs.esp.wrapping_sub(args_len) & !15).wrapping_sub(size_of::<u32>() as u32);
+
+        let mut raw = Vec::with_capacity(size_of::<u32>() + args.len() * size_of::<u32>());
+        raw.extend_from_slice(&0u32.to_ne_bytes());
+        for a in args { raw.extend_from_slice(&(*a as u32).to_ne_bytes()); }
+        RemoteMemory::new(pid).write(sp as u64, &raw)?;
+
+        regs.esp = sp;
+        regs.eip = func_addr as u32;
+
+        set_regs(pid, &regs)?;
+        let r = unsafe { libc::ptrace(libc::PTRACE_CONT, pid, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) };
+        if r < 0 { set_regs(pid, &backup).ok(); return Err(Error::last_os_error().into()); }
+
+        // Wait for SIGSEGV at eip 0
+        let trap = super::wait_for_trap(tracee, Signal::SIGSEGV, 0, read_pc)?;
+
+        get_regs(pid, &mut regs)?;
+        let ret = regs.eax as u64;
+
+        set_regs(pid, &backup)?;
+        trap.check(pid, || named_regs(&regs))?;
+
+        #[cfg(debug_assertions)]
+        eprintln!("[ptrace:x86] call_remote_function 0x{func_addr:x} -> 0x{ret:x}");
+
+        Ok(ret)
+    }
+}
+
+#[cfg(target_arch = "x86")]
+pub use archx86::{call_remote_function, run_to_entry};
+
+// No stub page on x86, the return-address trap is used for every call
+#[cfg(target_arch = "x86")]
+pub fn release_stub_page(_tracee: &mut Tracee, _libc_munmap_addr: u64) -> Result<()> {
+    Ok(())
+}
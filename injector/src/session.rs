@@ -0,0 +1,582 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use libc::pid_t;
+
+use crate::elf::{remote_exports, resolve_remote_internal_symbol, resolve_remote_symbol};
+use crate::error::{InjectError, Result};
+use crate::injector::{
+    call_dlopen, call_dlopen_memfd, call_dlsym, call_entry, call_errno_location, call_ifunc_resolver, call_with_args,
+    check_target_arch, eject_library, get_libc_path_for, get_linker_path_for};
+use crate::loader::{map_library, mapped_symbol};
+use crate::memory::RemoteMemory;
+use crate::ptrace::{
+    call_remote_function, kill_spawned, ptrace_attach, ptrace_attach_threads, ptrace_detach, ptrace_set_options,
+    redeliver_signals, release_stub_page, run_to_entry, spawn_traced, AttachMode, Tracee,
+};
+use crate::utils::{
+    disable_selinux, get_auxv_value, get_local_symbol_addr, get_pid, get_remote_function_addr, get_thread_ids,
+    is_selinux_enabled, AT_ENTRY,
+};
+use crate::zygote::wait_for_package;
+
+/// Builder for an injection session against a single process
+///
+/// ```no_run
+/// use injector::Injector;
+///
+/// let mut session = Injector::by_name("victim")?.attach()?;
+/// let handle = session.inject("/data/local/tmp/libhook.so")?;
+/// session.detach()?;
+/// # Ok::<(), injector::InjectError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Injector {
+    pid: pid_t,
+    libc_path: Option<String>,
+    linker_path: Option<String>,
+    disable_selinux: bool,
+    thread: Option<pid_t>,
+    stop_all_threads: bool,
+    attach_mode: AttachMode,
+    kill_on_exit: bool,
+    call_timeout: Option<Duration>,
+    entry: Option<(String, Vec<u8>)>,
+    origin: Origin,
+}
+
+// How the target came to us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Origin {
+    /// A running process, attached in `attach`
+    Running,
+    /// Started through `spawn`, already traced and stopped right after exec
+    Spawned,
+    /// Caught by `from_zygote`, already seized and stopped
+    Forked,
+}
+
+impl Injector {
+    pub fn new(pid: pid_t) -> Self {
+        Injector {
+            pid,
+            libc_path: None,
+            linker_path: None,
+            disable_selinux: false,
+            thread: None,
+            stop_all_threads: false,
+            attach_mode: AttachMode::default(),
+            kill_on_exit: false,
+            call_timeout: None,
+            entry: None,
+            origin: Origin::Running,
+        }
+    }
+
+    /// Look the target up by process name or full path, see `utils::get_pid`
+    pub fn by_name(process_name: &str) -> Result<Self> {
+        get_pid(process_name)
+            .map(Injector::new)
+            .ok_or_else(|| InjectError::ProcessNotFound(process_name.to_string()))
+    }
+
+    /// Start `program` with `args` under ptrace instead of going after a running process.
+    /// `attach` lets it run up to its executable's entry point (auxv AT_ENTRY): the loader
+    /// has mapped and initialised the libraries the program links against, `main` hasn't
+    /// run yet. The program carries on from there once the session detaches.
+    ///
+    /// Until `attach` the program sits stopped right after its exec. Should `attach`
+    /// fail, the program is killed rather than released half started
+    pub fn spawn(program: &str, args: &[&str]) -> Result<Self> {
+        let pid = spawn_traced(program, args)?;
+        Ok(Injector { origin: Origin::Spawned, ..Injector::new(pid) })
+    }
+
+    /// Android: wait for zygote to fork the app `package` and take it over as soon as it
+    /// has been specialized, before the app's own code (`Application.onCreate`) runs.
+    /// Apps are told apart by their cmdline, which becomes the package name (or
+    /// `package:process` for additional processes) during specialization.
+    ///
+    /// zygote and every other child it forks meanwhile are detached again, the app is
+    /// returned seized and stopped for `attach`. Blocks until the app is started, and
+    /// SELinux has to let us trace zygote (see `utils::disable_selinux`)
+    pub fn from_zygote(package: &str) -> Result<Self> {
+        let pid = wait_for_package(package)?;
+        Ok(Injector { origin: Origin::Forked, ..Injector::new(pid) })
+    }
+
+    pub fn pid(&self) -> pid_t {
+        self.pid
+    }
+
+    /// Override where libc lives, defaults to `injector::get_libc_path_for()` the target's word size
+    pub fn libc_path(mut self, path: &str) -> Self {
+        self.libc_path = Some(path.to_string());
+        self
+    }
+
+    /// Override where the dynamic linker lives, defaults to `injector::get_linker_path_for()` the target's word size
+    pub fn linker_path(mut self, path: &str) -> Self {
+        self.linker_path = Some(path.to_string());
+        self
+    }
+
+    /// Put SELinux into permissive mode before attaching, if it is present
+    pub fn disable_selinux(mut self, disable: bool) -> Self {
+        self.disable_selinux = disable;
+        self
+    }
+
+    /// Run the remote calls on thread `tid` of the target instead of its main thread
+    pub fn thread(mut self, tid: pid_t) -> Self {
+        self.thread = Some(tid);
+        self
+    }
+
+    /// Stop every thread of the target for the whole session, not just the one the
+    /// calls run on, so nothing races us while stubs and patches are written.
+    ///
+    /// A stopped thread may be holding a lock the injected code needs (the loader
+    /// lock, malloc's), in which case the remote dlopen never returns. Leave this
+    /// off unless the other threads actually get in the way
+    pub fn stop_all_threads(mut self, stop: bool) -> Self {
+        self.stop_all_threads = stop;
+        self
+    }
+
+    /// How to stop the target, see `AttachMode`. `AttachMode::Seize` avoids the
+    /// SIGSTOP the target (and its parent's job control) would otherwise see
+    pub fn attach_mode(mut self, mode: AttachMode) -> Self {
+        self.attach_mode = mode;
+        self
+    }
+
+    /// Have the kernel kill the target if the injector goes away while still
+    /// attached (PTRACE_O_EXITKILL), instead of leaving it mid remote call
+    pub fn kill_on_exit(mut self, kill: bool) -> Self {
+        self.kill_on_exit = kill;
+        self
+    }
+
+    /// Give up on a remote call that hasn't returned after `timeout`, e.g. a dlopen stuck
+    /// in a library constructor. The thread is interrupted, put back where it was and the
+    /// call fails with `RemoteCallTimedOut`. Whatever the abandoned call held (the loader
+    /// lock, say) stays held, so treat the target as suspect afterwards
+    pub fn call_timeout(mut self, timeout: Duration) -> Self {
+        self.call_timeout = Some(timeout);
+        self
+    }
+
+    pub fn attach(self) -> Result<Session> {
+        let (pid, origin) = (self.pid, self.origin);
+        self.attach_target().inspect_err(|_| match origin {
+            Origin::Running => {}
+            Origin::Spawned => kill_spawned(pid),
+            // the app carries on as if we had never been there
+            Origin::Forked => {
+                let _ = ptrace_detach(pid);
+            }
+        })
+    }
+
+    fn attach_target(self) -> Result<Session> {
+        if self.disable_selinux && is_selinux_enabled() {
+            disable_selinux();
+        }
+
+        let compat = check_target_arch(self.pid)?;
+        let bits = if compat { 32 } else { usize::BITS };
+        if compat {
+            vlog!("[session] pid={} is a 32-bit process, driving it in compat mode", self.pid);
+        }
+
+        let tid = self.thread.unwrap_or(self.pid);
+        if !get_thread_ids(self.pid).contains(&tid) {
+            return Err(InjectError::InvalidArgument(format!("{} is not a thread of process {}", tid, self.pid)));
+        }
+
+        let options = if self.kill_on_exit { libc::PTRACE_O_EXITKILL } else { 0 };
+        if self.origin == Origin::Running {
+            ptrace_attach(tid, self.attach_mode, options)?;
+        } else if options != 0 {
+            ptrace_set_options(tid, options)?;
+        }
+        let threads = if self.stop_all_threads {
+            ptrace_attach_threads(self.pid, &[tid], self.attach_mode, options).inspect_err(|_| {
+                let _ = ptrace_detach(tid);
+            })?
+        } else {
+            Vec::new()
+        };
+
+        let mut tracee = Tracee::new(tid, compat);
+        tracee.seized = match self.origin {
+            Origin::Running => self.attach_mode == AttachMode::Seize,
+            Origin::Spawned => false,
+            Origin::Forked => true,
+        };
+        if self.origin == Origin::Spawned {
+            let entry = get_auxv_value(self.pid, bits, AT_ENTRY)
+                .ok_or_else(|| InjectError::InvalidArgument(format!("no AT_ENTRY in the auxv of {}", self.pid)))?;
+            vlog!("[session] running spawned pid={} up to its entry point 0x{:x}", self.pid, entry);
+            run_to_entry(&mut tracee, entry)?;
+        }
+        // only now, getting to the entry point is up to the loader and no remote call
+        tracee.call_timeout = self.call_timeout;
+
+        Ok(Session {
+            pid: self.pid,
+            tracee,
+            threads,
+            libc_path: self.libc_path.unwrap_or_else(|| get_libc_path_for(bits)),
+            linker_path: self.linker_path.unwrap_or_else(|| get_linker_path_for(bits)),
+            mmap_addr: None,
+            symbols: RefCell::new(HashMap::new()),
+            allocations: Vec::new(),
+            attached: true,
+        })
+    }
+
+    /// Once the library is loaded, call its exported `symbol` as
+    /// `int symbol(const void *payload, size_t len)` with a copy of `payload`, e.g. a
+    /// configuration telling the library what to do in this target. A status other
+    /// than 0 fails the injection with `RemoteCallFailed`, the library stays loaded
+    pub fn entry(mut self, symbol: &str, payload: &[u8]) -> Self {
+        self.entry = Some((symbol.to_string(), payload.to_vec()));
+        self
+    }
+
+    /// One shot attach, dlopen and detach
+    pub fn inject(self, library_path: &str) -> Result<u64> {
+        self.inject_with(library_path, Session::inject, Session::dlsym)
+    }
+
+    /// One shot attach, dlopen from a memfd (see `Session::inject_memfd`) and detach
+    pub fn inject_memfd(self, library_path: &str) -> Result<u64> {
+        self.inject_with(library_path, Session::inject_memfd, Session::dlsym)
+    }
+
+    /// One shot attach, manual mapping (see `Session::inject_manual`) and detach
+    pub fn inject_manual(self, library_path: &str) -> Result<u64> {
+        self.inject_with(library_path, Session::inject_manual, |_, base, symbol| {
+            mapped_symbol(library_path, base, symbol)
+        })
+    }
+
+    // Attach, `load` the library, run the `entry` step if there is one (`find_entry` looks
+    // the symbol up from what `load` returned) and detach
+    fn inject_with(
+        mut self,
+        library_path: &str,
+        load: impl FnOnce(&mut Session, &str) -> Result<u64>,
+        find_entry: impl FnOnce(&mut Session, u64, &str) -> Result<u64>,
+    ) -> Result<u64> {
+        let entry = self.entry.take();
+        let mut session = self.attach()?;
+        let result = load(&mut session, library_path).and_then(|handle| {
+            let Some((symbol, payload)) = entry else {
+                return Ok(handle);
+            };
+            let addr = find_entry(&mut session, handle, &symbol)?;
+            match session.call_entry(addr, &payload)? {
+                0 => Ok(handle),
+                status => Err(InjectError::RemoteCallFailed { function: symbol, ret: status as i64 }),
+            }
+        });
+        // Always let go of the target, even if the injection itself failed. A detach
+        // error is only worth reporting when there's no injection error to report
+        let detached = session.detach();
+        let handle = result?;
+        detached.map(|_| handle)
+    }
+
+    /// One shot attach, dlclose and detach
+    pub fn eject(self, library_path: &str) -> Result<()> {
+        let mut session = self.attach()?;
+        let result = session.eject(library_path, None);
+        let detached = session.detach();
+        result?;
+        detached
+    }
+}
+
+/// An argument for `Session::call_args`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallArg {
+    /// Passed as is, truncated to the target's word size
+    Int(u64),
+    /// Copied into the target as a NUL-terminated string, passed as a pointer to it
+    Str(String),
+}
+
+/// An attached, stopped target. Dropping the session detaches from it
+#[derive(Debug)]
+pub struct Session {
+    pid: pid_t,
+    tracee: Tracee,
+    // other threads stopped alongside the one we drive
+    threads: Vec<pid_t>,
+    libc_path: String,
+    linker_path: String,
+    // remote mmap, needed to bootstrap the call stubs
+    mmap_addr: Option<u64>,
+    // (module, symbol) -> remote address, parsing libc on every call adds up
+    symbols: RefCell<HashMap<(String, String), u64>>,
+    // remote mmaps made for the session that are still mapped
+    allocations: Vec<(u64, usize)>,
+    attached: bool,
+}
+
+impl Session {
+    pub fn pid(&self) -> pid_t {
+        self.pid
+    }
+
+    /// Thread the remote calls run on
+    pub fn tid(&self) -> pid_t {
+        self.tracee.pid
+    }
+
+    /// Word size of the target, 32 for a compat process under a 64-bit injector
+    pub fn bits(&self) -> u32 {
+        if self.tracee.compat {
+            32
+        } else {
+            usize::BITS
+        }
+    }
+
+    pub fn libc_path(&self) -> &str {
+        &self.libc_path
+    }
+
+    pub fn linker_path(&self) -> &str {
+        &self.linker_path
+    }
+
+    /// Change the timeout for the following remote calls, None waits forever.
+    /// See `Injector::call_timeout`
+    pub fn set_call_timeout(&mut self, timeout: Option<Duration>) {
+        self.tracee.call_timeout = timeout;
+    }
+
+    /// Read and write the target's memory
+    pub fn memory(&self) -> RemoteMemory {
+        // the traced thread, PEEK/POKE only work on a stopped tracee
+        RemoteMemory::new(self.tid())
+    }
+
+    /// Address of `symbol` from `module` inside the target. The module file the
+    /// target has mapped is parsed first, if that fails we fall back to offsetting
+    /// the injector's own copy of the module. A compat target never shares our
+    /// copy, so there it's the ELF lookup or nothing
+    pub fn resolve(&self, module: &str, symbol: &str) -> Result<u64> {
+        let key = (module.to_string(), symbol.to_string());
+        if let Some(addr) = self.symbols.borrow().get(&key) {
+            return Ok(*addr);
+        }
+
+        let addr = resolve_remote_symbol(self.pid(), module, symbol)
+            .or_else(|| {
+                if self.tracee.compat {
+                    return None;
+                }
+                vlog!("[resolve] ELF lookup of {}!{} failed, trying local offset", module, symbol);
+                get_local_symbol_addr(module, symbol)
+                    .and_then(|local| get_remote_function_addr(self.pid(), module, local))
+            })
+            .ok_or_else(|| InjectError::SymbolNotResolved {
+                module: module.to_string(),
+                symbol: symbol.to_string(),
+            })?;
+
+        self.symbols.borrow_mut().insert(key, addr);
+        Ok(addr)
+    }
+
+    /// Like `resolve`, for a function about to be called: a GNU IFUNC (e.g. glibc's
+    /// string functions) resolves to its resolver, which is run here to get the
+    /// implementation the target would use
+    pub fn resolve_function(&mut self, module: &str, symbol: &str) -> Result<u64> {
+        match remote_exports(self.pid(), module).and_then(|exports| exports.get(symbol).copied()) {
+            Some(export) if export.ifunc => call_ifunc_resolver(self, export.addr),
+            Some(export) => Ok(export.addr),
+            None => self.resolve(module, symbol),
+        }
+    }
+
+    /// Address of `symbol` from `module` that the module doesn't export but still lists
+    /// in `.symtab`, e.g. functions internal to Android's linker. There is no local copy
+    /// to fall back on, the target's module file has to be readable
+    pub fn resolve_internal(&self, module: &str, symbol: &str) -> Result<u64> {
+        resolve_remote_internal_symbol(self.pid(), module, symbol).ok_or_else(|| InjectError::SymbolNotResolved {
+            module: module.to_string(),
+            symbol: symbol.to_string(),
+        })
+    }
+
+    /// Call `func_addr` in the target with integer/pointer arguments and return its result.
+    /// Should the function crash or time out, the target is rolled back (see `rollback`)
+    /// before the `RemoteCallCrashed`/`RemoteCallTimedOut` is returned
+    pub fn call(&mut self, func_addr: u64, args: &[u64]) -> Result<u64> {
+        let mmap_addr = match self.mmap_addr {
+            Some(addr) => addr,
+            None => {
+                let addr = self.resolve(&self.libc_path, "mmap")?;
+                self.mmap_addr = Some(addr);
+                addr
+            }
+        };
+
+        let result = call_remote_function(&mut self.tracee, func_addr, args, mmap_addr);
+        match &result {
+            Err(InjectError::RemoteCallCrashed(report)) => {
+                vlog!("[session] remote call to 0x{:x} crashed with {:?}, rolling back", func_addr, report.signal);
+                self.rollback(mmap_addr);
+            }
+            Err(InjectError::RemoteCallTimedOut { .. }) => {
+                vlog!("[session] remote call to 0x{:x} timed out, rolling back", func_addr);
+                self.rollback(mmap_addr);
+            }
+            _ => {}
+        }
+        result
+    }
+
+    /// `call` with strings among the arguments. They are copied into memory mapped for
+    /// the call and unmapped again once it returns, so the callee must not keep them
+    pub fn call_args(&mut self, func_addr: u64, args: &[CallArg]) -> Result<u64> {
+        call_with_args(self, func_addr, args)
+    }
+
+    /// errno of the thread the calls run on, as left by the last call
+    pub fn errno(&mut self) -> Result<i32> {
+        let location = call_errno_location(self)?;
+        let bytes = self.memory().read(location, 4)?;
+        Ok(i32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Set the calling thread's errno, e.g. to 0 before a call whose errno is wanted
+    pub fn set_errno(&mut self, value: i32) -> Result<()> {
+        let location = call_errno_location(self)?;
+        self.memory().write(location, &value.to_le_bytes())
+    }
+
+    /// Undo what the session did to the target: allocations and the stub page are
+    /// unmapped, registers are restored by every call already. Everything the injector
+    /// writes (stubs, strings, manually mapped libraries) lives in those mappings, so
+    /// nothing else of the target's memory needs putting back. Not covered: the stack
+    /// below sp, scratch space the called code clobbers anyway, and whatever the crashed
+    /// code itself changed, locks it took included. Best effort, failing steps are logged
+    fn rollback(&mut self, mmap_addr: u64) {
+        let munmap = match self.resolve(&self.libc_path, "munmap") {
+            Ok(addr) => addr,
+            Err(e) => {
+                eprintln!("[session] rollback: {}, leaving allocations mapped", e);
+                return;
+            }
+        };
+        for (addr, len) in std::mem::take(&mut self.allocations) {
+            if let Err(e) = call_remote_function(&mut self.tracee, munmap, &[addr, len as u64], mmap_addr) {
+                eprintln!("[session] rollback: munmap 0x{:x} failed: {}", addr, e);
+            }
+        }
+        if let Err(e) = release_stub_page(&mut self.tracee, munmap) {
+            eprintln!("[session] rollback: releasing the stub page failed: {}", e);
+        }
+    }
+
+    /// Remember a remote mmap made on the session's behalf, see `rollback`
+    pub(crate) fn allocated(&mut self, addr: u64, len: usize) {
+        self.allocations.push((addr, len));
+    }
+
+    pub(crate) fn freed(&mut self, addr: u64) {
+        self.allocations.retain(|(a, _)| *a != addr);
+    }
+
+    /// Whether `addr` is still mapped on the session's behalf, false once a rollback
+    /// unmapped it
+    pub(crate) fn is_allocated(&self, addr: u64) -> bool {
+        self.allocations.iter().any(|(a, _)| *a == addr)
+    }
+
+    /// dlopen `library_path` inside the target, returning the handle
+    pub fn inject(&mut self, library_path: &str) -> Result<u64> {
+        let result = call_dlopen(self, library_path);
+
+        #[cfg(debug_assertions)]
+        {
+            if result.is_err() {
+                eprintln!("Injection failed...");
+            } else {
+                eprintln!("Injection ended successfully...");
+            }
+        }
+
+        result
+    }
+
+    /// Like `inject`, but the target never opens `library_path`: the injector reads it
+    /// and the target loads it from a memfd holding a copy, so the library can be
+    /// anywhere the injector can read, whatever its SELinux label
+    pub fn inject_memfd(&mut self, library_path: &str) -> Result<u64> {
+        call_dlopen_memfd(self, library_path)
+    }
+
+    /// Load `library_path` without the target's dynamic linker: the library is mapped,
+    /// relocated against the modules already loaded in the target and initialized by
+    /// the injector itself. Returns the load address, there is no handle since the
+    /// linker doesn't know the library, so it can't be ejected either. Libraries using
+    /// thread-local storage can't be loaded this way
+    pub fn inject_manual(&mut self, library_path: &str) -> Result<u64> {
+        map_library(self, library_path)
+    }
+
+    /// Address of `symbol` in a library loaded with `inject`/`inject_memfd`, through the
+    /// target's own dlsym
+    pub fn dlsym(&mut self, handle: u64, symbol: &str) -> Result<u64> {
+        call_dlsym(self, handle, symbol)
+    }
+
+    /// Call `entry` as `int entry(const void *payload, size_t len)` with a copy of
+    /// `payload` in the target and return its status, see `Injector::entry`
+    pub fn call_entry(&mut self, entry: u64, payload: &[u8]) -> Result<i32> {
+        call_entry(self, entry, payload)
+    }
+
+    /// Unload a library injected earlier. Pass the handle `inject` returned if you
+    /// still have it, otherwise it is looked up by path in the target
+    pub fn eject(&mut self, library_path: &str, handle: Option<u64>) -> Result<()> {
+        eject_library(self, library_path, handle)
+    }
+
+    /// Resume every thread we stopped. All of them are let go even if one fails,
+    /// the first error is reported. Signals the target received while our calls
+    /// were running are sent to it again afterwards
+    pub fn detach(mut self) -> Result<()> {
+        self.attached = false;
+        self.release()
+    }
+
+    fn release(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for tid in self.threads.iter().copied().chain([self.tid()]) {
+            if let Err(e) = ptrace_detach(tid) {
+                vlog!("[session] detaching thread {} failed: {}", tid, e);
+                result = result.and(Err(e));
+            }
+        }
+        redeliver_signals(self.pid, self.tid(), &self.tracee.take_pending_signals());
+        result
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if self.attached {
+            let _ = self.release();
+        }
+    }
+}
@@ -1,10 +1,11 @@
 use std::env;
+use std::ffi::CString;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read, Write};
 use std::path::Path;
 use std::process;
 
-fn verbose() -> bool {
+pub(crate) fn verbose() -> bool {
     // Any presence of INJECT_VERBOSE enables logs
     env::var("INJECT_VERBOSE").is_ok()
 }
@@ -17,16 +18,10 @@ macro_rules! vlog {
     }
 }
 
-/// ---------- PID helpers ----------
+// ---------- PID helpers ----------
 
 pub fn get_pid(process_name: &str) -> Option<i32> {
-    let target = process_name;
-    let target_base = Path::new(process_name)
-        .file_name()
-        .and_then(|s| s.to_str())
-        .unwrap_or(process_name);
-
-    vlog!("[pid] looking for process: target='{}' base='{}'", target, target_base);
+    vlog!("[pid] looking for process: '{}'", process_name);
 
     let entries = std::fs::read_dir("/proc").ok()?;
 
@@ -36,52 +31,125 @@ pub fn get_pid(process_name: &str) -> Option<i32> {
             _ => continue,
         };
 
-        // 1) /proc/<pid>/comm
-        let comm_path = format!("/proc/{}/comm", pid);
-        if let Ok(mut f) = File::open(&comm_path) {
-            let mut s = String::new();
-            if f.read_to_string(&mut s).is_ok() {
-                let name = s.trim();
-                if name == target || name == target_base {
-                    vlog!("[pid] match via comm: pid={} name='{}'", pid, name);
-                    return Some(pid);
-                }
-            }
+        if process_name_matches(pid, process_name) {
+            return Some(pid);
         }
+    }
 
-        // 2) /proc/<pid>/cmdline
-        let cmdline_path = format!("/proc/{}/cmdline", pid);
-        if let Ok(mut file) = File::open(&cmdline_path) {
-            let mut contents = String::new();
-            if file.read_to_string(&mut contents).is_ok() {
-                if let Some(first) = contents.split('\0').next() {
-                    let first_base = Path::new(first)
-                        .file_name()
-                        .and_then(|s| s.to_str())
-                        .unwrap_or(first);
-                    if first == target || first_base == target_base {
-                        vlog!(
-                            "[pid] match via cmdline: pid={} argv0='{}' base='{}'",
-                            pid,
-                            first,
-                            first_base
-                        );
-                        return Some(pid);
-                    }
-                }
+    vlog!("[pid] no matching process found for '{}'", process_name);
+    None
+}
+
+/// Whether `pid` goes by `process_name`, a name or full path compared against its comm
+/// and argv[0], as is and by file name
+pub fn process_name_matches(pid: i32, process_name: &str) -> bool {
+    let target = process_name;
+    let target_base = Path::new(process_name)
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or(process_name);
+
+    // 1) /proc/<pid>/comm
+    let comm_path = format!("/proc/{}/comm", pid);
+    if let Ok(mut f) = File::open(&comm_path) {
+        let mut s = String::new();
+        if f.read_to_string(&mut s).is_ok() {
+            let name = s.trim();
+            if name == target || name == target_base {
+                vlog!("[pid] match via comm: pid={} name='{}'", pid, name);
+                return true;
             }
         }
     }
 
-    vlog!("[pid] no matching process found for '{}'", process_name);
-    None
+    // 2) /proc/<pid>/cmdline
+    if let Some(first) = get_cmdline(pid).and_then(|args| args.into_iter().next()) {
+        let first_base = Path::new(&first)
+            .file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or(&first);
+        if first == target || first_base == target_base {
+            vlog!(
+                "[pid] match via cmdline: pid={} argv0='{}' base='{}'",
+                pid,
+                first,
+                first_base
+            );
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Command line of `pid` split into its arguments, empty for kernel threads
+pub fn get_cmdline(pid: i32) -> Option<Vec<String>> {
+    let raw = std::fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    if raw.is_empty() {
+        return Some(Vec::new());
+    }
+    // every argument is NUL terminated, the last one included
+    let raw = raw.strip_suffix(&[0]).unwrap_or(&raw);
+    Some(raw.split(|&b| b == 0).map(|arg| String::from_utf8_lossy(arg).into_owned()).collect())
+}
+
+// Field `n` of /proc/<pid>/stat, numbered like proc(5) does. comm (field 2) may contain
+// anything, spaces and parentheses included, so fields are counted from its last ')'
+fn get_stat_field(pid: i32, n: usize) -> Option<String> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    stat.rsplit_once(')')?.1.split_whitespace().nth(n.checked_sub(3)?).map(str::to_string)
 }
 
-/// ---------- SELinux helpers ----------
+/// Parent of `pid`, from /proc/<pid>/stat
+pub fn get_parent_pid(pid: i32) -> Option<i32> {
+    get_stat_field(pid, 4)?.parse().ok()
+}
+
+/// When `pid` was started, in clock ticks since boot
+pub fn get_start_time(pid: i32) -> Option<u64> {
+    get_stat_field(pid, 22)?.parse().ok()
+}
+
+/// Security context `pid` runs in from /proc/<pid>/attr/current, the SELinux one on
+/// Android. None if no LSM provides one
+pub fn get_selinux_context(pid: i32) -> Option<String> {
+    let context = std::fs::read_to_string(format!("/proc/{}/attr/current", pid)).ok()?;
+    let context = context.trim_end_matches(['\0', '\n']);
+    (!context.is_empty()).then(|| context.to_string())
+}
+
+/// Real uid `pid` runs as, from /proc/<pid>/status
+pub fn get_process_uid(pid: i32) -> Option<u32> {
+    let file = File::open(format!("/proc/{}/status", pid)).ok()?;
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .find_map(|line| line.strip_prefix("Uid:")?.split_whitespace().next()?.parse().ok())
+}
+
+/// Thread ids of `pid` as listed in /proc/<pid>/task, the leader included
+pub fn get_thread_ids(pid: i32) -> Vec<i32> {
+    let entries = match std::fs::read_dir(format!("/proc/{}/task", pid)) {
+        Ok(e) => e,
+        Err(e) => {
+            vlog!("[pid] unable to list threads of {}: {}", pid, e);
+            return Vec::new();
+        }
+    };
+
+    let mut tids: Vec<i32> = entries
+        .flatten()
+        .filter_map(|e| e.file_name().to_string_lossy().parse::<i32>().ok())
+        .collect();
+    tids.sort_unstable();
+    tids
+}
+
+// ---------- SELinux helpers ----------
 
 pub fn is_selinux_enabled() -> bool {
     if let Ok(file) = File::open("/proc/filesystems") {
-        for line in BufReader::new(file).lines().flatten() {
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
             if line.contains("selinuxfs") {
                 vlog!("[selinux] selinuxfs present");
                 return true;
@@ -94,7 +162,7 @@ pub fn is_selinux_enabled() -> bool {
 
 pub fn disable_selinux() {
     if let Ok(file) = File::open("/proc/mounts") {
-        for line in BufReader::new(file).lines().flatten() {
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
             if line.contains("selinuxfs") {
                 if let Some(mount_point) = line.split_whitespace().nth(1) {
                     let path = format!("{}/enforce", mount_point);
@@ -111,7 +179,92 @@ pub fn disable_selinux() {
     }
 }
 
-/// ---------- Address helpers (public API uses u64 to match other modules) ----------
+// ---------- Architecture helpers ----------
+
+/// Architecture of an ELF binary from its header, named like `std::env::consts::ARCH`
+pub fn get_elf_arch(path: &str) -> Option<String> {
+    let mut header = [0u8; 20];
+    File::open(path).ok()?.read_exact(&mut header).ok()?;
+    if &header[..4] != b"\x7fELF" {
+        vlog!("[arch] {} is not an ELF file", path);
+        return None;
+    }
+
+    let class = header[4];
+    let machine = u16::from_le_bytes([header[18], header[19]]);
+    let arch = match (class, machine) {
+        (2, 183) => "aarch64".to_string(),
+        (1, 40) => "arm".to_string(),
+        (2, 62) => "x86_64".to_string(),
+        (1, 3) => "x86".to_string(),
+        _ => format!("elf class {} machine {}", class, machine),
+    };
+    vlog!("[arch] {} -> {}", path, arch);
+    Some(arch)
+}
+
+/// Architecture of a running process, taken from /proc/<pid>/exe
+pub fn get_process_arch(pid: i32) -> Option<String> {
+    get_elf_arch(&format!("/proc/{}/exe", pid))
+}
+
+/// Word size (32 or 64) of a running process. The ELF class of /proc/<pid>/exe is
+/// used when it can be read, the layout of /proc/<pid>/auxv otherwise
+pub fn get_process_bitness(pid: i32) -> Option<u32> {
+    let mut ident = [0u8; 5];
+    let from_exe = File::open(format!("/proc/{}/exe", pid))
+        .and_then(|mut f| f.read_exact(&mut ident))
+        .ok()
+        .filter(|_| &ident[..4] == b"\x7fELF")
+        .and_then(|_| match ident[4] {
+            1 => Some(32),
+            2 => Some(64),
+            _ => None,
+        });
+    if let Some(bits) = from_exe {
+        vlog!("[arch] pid={} is {} bit (exe)", pid, bits);
+        return Some(bits);
+    }
+
+    // auxv is (type, value) pairs of the process' own word size. Read as 64-bit
+    // pairs, the types of a 32-bit process pick up the neighbouring value in their
+    // upper half, while real a_type values are all small
+    let mut auxv = Vec::new();
+    File::open(format!("/proc/{}/auxv", pid)).ok()?.read_to_end(&mut auxv).ok()?;
+    if auxv.is_empty() {
+        return None;
+    }
+    let is_64 = auxv.len() % 16 == 0
+        && auxv
+            .chunks_exact(16)
+            .all(|pair| u64::from_ne_bytes(pair[..8].try_into().unwrap()) < 0x100);
+    let bits = if is_64 { 64 } else { 32 };
+    vlog!("[arch] pid={} is {} bit (auxv)", pid, bits);
+    Some(bits)
+}
+
+// libc has these as c_ulong, which changes width with the injector's own arch
+const AT_NULL: u64 = 0;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_HWCAP: u64 = 16;
+
+/// Value of auxv entry `key` (AT_*) of `pid`, whose word size is `bits`
+pub fn get_auxv_value(pid: i32, bits: u32, key: u64) -> Option<u64> {
+    let mut auxv = Vec::new();
+    File::open(format!("/proc/{}/auxv", pid)).ok()?.read_to_end(&mut auxv).ok()?;
+    let word = (bits / 8) as usize;
+    let read_word = |b: &[u8]| match word {
+        8 => u64::from_ne_bytes(b.try_into().unwrap()),
+        _ => u32::from_ne_bytes(b.try_into().unwrap()) as u64,
+    };
+    auxv.chunks_exact(word * 2)
+        .map(|pair| (read_word(&pair[..word]), read_word(&pair[word..])))
+        .take_while(|&(ty, _)| ty != AT_NULL)
+        .find(|&(ty, _)| ty == key)
+        .map(|(_, value)| value)
+}
+
+// ---------- Address helpers (public API uses u64 to match other modules) ----------
 
 #[inline]
 fn parse_hex_addr_to_u64(s: &str) -> Option<u64> {
@@ -123,7 +276,7 @@ fn dump_module_candidates_from_maps(pid: i32, module_name: &str) {
 
     if let Ok(file) = File::open(&maps_path) {
         eprintln!("[maps] candidates for pid={} module='{}':", pid, module_name);
-        for line in BufReader::new(file).lines().flatten() {
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
             if line.contains(module_name) {
                 eprintln!("  {}", line);
             }
@@ -133,6 +286,54 @@ fn dump_module_candidates_from_maps(pid: i32, module_name: &str) {
     }
 }
 
+/// A maps entry belongs to `module_name` if it is the same path, has the same
+/// file name or contains it (e.g. a short library name)
+fn maps_path_matches(path: &str, module_name: &str) -> bool {
+    !path.is_empty()
+        && (path == module_name
+            || Path::new(path)
+                .file_name()
+                .and_then(|s| s.to_str())
+                .map(|bn| bn == module_name)
+                .unwrap_or(false)
+            || path.contains(module_name))
+}
+
+/// Load address and backing file of `module_name` in `pid`. The mapping of the
+/// ELF header (file offset 0) is preferred since that is what load biases are
+/// relative to
+pub fn get_module_mapping(pid: i32, module_name: &str) -> Option<(u64, String)> {
+    let maps_path = format!("/proc/{}/maps", pid);
+    let file = File::open(&maps_path).ok()?;
+
+    let mut first: Option<(u64, String)> = None;
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        let mut parts = line.split_whitespace();
+        let (Some(range), Some(_perms), Some(offset), Some(_dev), Some(_inode), Some(path)) =
+            (parts.next(), parts.next(), parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        if !maps_path_matches(path, module_name) {
+            continue;
+        }
+
+        let Some(base) = range.split('-').next().and_then(parse_hex_addr_to_u64) else {
+            continue;
+        };
+        if parse_hex_addr_to_u64(offset) == Some(0) {
+            vlog!("[maps] mapping of '{}' in pid={}: 0x{:x} {}", module_name, pid, base, path);
+            return Some((base, path.to_string()));
+        }
+        if first.is_none() {
+            first = Some((base, path.to_string()));
+        }
+    }
+
+    vlog!("[maps] no offset 0 mapping of '{}' in pid={}, using {:?}", module_name, pid, first);
+    first
+}
+
 pub fn get_module_base_addr(pid: i32, module_name: &str) -> Option<u64> {
     let maps_path = format!("/proc/{}/maps", pid);
 
@@ -149,7 +350,7 @@ pub fn get_module_base_addr(pid: i32, module_name: &str) -> Option<u64> {
 
     let mut first_match_line: Option<String> = None;
 
-    for line in reader.lines().flatten() {
+    for line in reader.lines().map_while(Result::ok) {
         // Example: 12c00000-12d00000 r-xp 00000000 fc:00 12345 /system/lib/libc.so
         let mut parts = line.split_whitespace();
         let range = match parts.next() {
@@ -162,16 +363,8 @@ pub fn get_module_base_addr(pid: i32, module_name: &str) -> Option<u64> {
         let path = parts.next_back();
 
         let path_match = match path {
-            Some(p) if !p.is_empty() => {
-                p == module_name
-                    || Path::new(p)
-                        .file_name()
-                        .and_then(|s| s.to_str())
-                        .map(|bn| bn == module_name)
-                        .unwrap_or(false)
-                    || p.contains(module_name)
-            }
-            _ => false,
+            Some(p) => maps_path_matches(p, module_name),
+            None => false,
         };
 
         if path_match {
@@ -205,6 +398,63 @@ pub fn get_module_base_addr(pid: i32, module_name: &str) -> Option<u64> {
     None
 }
 
+/// Whether any mapping of `pid` is backed by exactly `path`
+pub fn is_module_mapped(pid: i32, path: &str) -> bool {
+    let maps_path = format!("/proc/{}/maps", pid);
+    let file = match File::open(&maps_path) {
+        Ok(f) => f,
+        Err(e) => {
+            vlog!("[maps] open failed: {} ({})", maps_path, e);
+            return false;
+        }
+    };
+
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .any(|line| line.split_whitespace().nth(5) == Some(path))
+}
+
+/// Start and path of the first executable file mapping of `pid` whose path passes `wanted`
+pub fn find_code_mapping(pid: i32, wanted: impl Fn(&str) -> bool) -> Option<(u64, String)> {
+    let file = File::open(format!("/proc/{}/maps", pid)).ok()?;
+    BufReader::new(file).lines().map_while(Result::ok).find_map(|line| {
+        let mut parts = line.split_whitespace();
+        let (Some(range), Some(perms), Some(path)) = (parts.next(), parts.next(), parts.nth(3)) else {
+            return None;
+        };
+        if !perms.contains('x') || !wanted(path) {
+            return None;
+        }
+        let start = range.split('-').next().and_then(parse_hex_addr_to_u64)?;
+        Some((start, path.to_string()))
+    })
+}
+
+/// Address of `symbol` inside `module` as loaded in the injector itself. The module
+/// has to be loaded already, we never pull a library in just to look something up
+pub fn get_local_symbol_addr(module_name: &str, symbol: &str) -> Option<u64> {
+    let c_module = CString::new(module_name).ok()?;
+    let c_symbol = CString::new(symbol).ok()?;
+
+    let handle = unsafe { libc::dlopen(c_module.as_ptr(), libc::RTLD_NOW | libc::RTLD_NOLOAD) };
+    if handle.is_null() {
+        vlog!("[resolve] '{}' is not loaded locally", module_name);
+        return None;
+    }
+
+    let addr = unsafe { libc::dlsym(handle, c_symbol.as_ptr()) };
+    // drop the reference NOLOAD took
+    unsafe { libc::dlclose(handle) };
+
+    if addr.is_null() {
+        vlog!("[resolve] '{}' not exported by local '{}'", symbol, module_name);
+        return None;
+    }
+    vlog!("[resolve] local {}!{} = {:p}", module_name, symbol, addr);
+    Some(addr as u64)
+}
+
 pub fn get_remote_function_addr(remote_pid: i32, module_name: &str, local_addr: u64) -> Option<u64> {
     vlog!(
         "[resolve] remote_pid={} module='{}' local_addr=0x{:x}",
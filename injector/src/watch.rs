@@ -0,0 +1,372 @@
+//! Waiting for processes to show up, for targets that come and go too quickly to be
+//! picked by hand
+//!
+//! New processes are learned about from the kernel's proc connector (netlink) when we
+//! are allowed to listen to it, by rescanning /proc otherwise.
+
+use std::collections::HashSet;
+use std::io::{self, Error};
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc::{c_void, pid_t};
+
+use crate::error::Result;
+use crate::injector::get_libc_path_for;
+use crate::process::{list_pids, ProcessFilter};
+use crate::utils::{get_module_mapping, get_parent_pid, get_process_bitness};
+
+/// Reports processes matching a `ProcessFilter`, each one once
+///
+/// ```no_run
+/// use injector::process::ProcessFilter;
+/// use injector::watch::Watcher;
+/// use injector::Injector;
+///
+/// let mut watcher = Watcher::new(ProcessFilter::new().name("worker"));
+/// loop {
+///     let pid = watcher.next_match()?;
+///     Injector::new(pid).inject("/data/local/tmp/libhook.so")?;
+/// }
+/// # Ok::<(), injector::InjectError>(())
+/// ```
+#[derive(Debug)]
+pub struct Watcher {
+    filter: ProcessFilter,
+    new_only: bool,
+    interval: Duration,
+    // None until the first `next_match`, then None again if the connector fails us
+    connector: Option<ProcConnector>,
+    started: bool,
+    // matching processes reported already (or left out by `new_only`)
+    seen: HashSet<pid_t>,
+    // we and the processes we run under (sudo, a wrapper script), whose command
+    // lines tend to contain whatever we are looking for
+    ourselves: HashSet<pid_t>,
+    // matching processes whose libc isn't mapped yet, too early to inject (having it
+    // mapped is necessary, not sufficient, see `next_match`)
+    loading: Vec<pid_t>,
+}
+
+// Polling interval without the proc connector, and while a match is still loading
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(20);
+const LOADING_INTERVAL: Duration = Duration::from_millis(1);
+
+impl Watcher {
+    pub fn new(filter: ProcessFilter) -> Self {
+        Watcher {
+            filter,
+            new_only: false,
+            interval: DEFAULT_INTERVAL,
+            connector: None,
+            started: false,
+            seen: HashSet::new(),
+            ourselves: HashSet::new(),
+            loading: Vec::new(),
+        }
+    }
+
+    /// Skip processes that are already running when watching starts, only report ones
+    /// that show up afterwards
+    pub fn new_only(mut self, new_only: bool) -> Self {
+        self.new_only = new_only;
+        self
+    }
+
+    /// How often /proc is rescanned when the proc connector isn't available
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Block until a matching process we haven't reported yet is ready to be injected,
+    /// meaning it has its libc mapped. A freshly exec'd process is still in the
+    /// dynamic loader for a moment, a remote dlopen can't work before that.
+    ///
+    /// Having libc mapped doesn't mean the loader is done with it though: it may still
+    /// be relocating libc or the libraries after it, and an injection in that window
+    /// can fail (symbols not found, mmap failing) or crash. Failures that didn't get
+    /// to run anything in the target are worth retrying for a process just reported
+    pub fn next_match(&mut self) -> Result<pid_t> {
+        if !self.started {
+            self.start();
+        }
+
+        loop {
+            if let Some(pid) = self.take_ready() {
+                return Ok(pid);
+            }
+
+            let wait = if self.loading.is_empty() { self.interval } else { LOADING_INTERVAL };
+            let Some(connector) = &self.connector else {
+                thread::sleep(wait);
+                self.rescan();
+                continue;
+            };
+
+            match connector.wait(wait) {
+                Ok(events) => {
+                    for event in events {
+                        match event {
+                            ProcEvent::Started(pid) => self.consider(pid),
+                            ProcEvent::Exited(pid) => {
+                                self.seen.remove(&pid);
+                            }
+                        }
+                    }
+                }
+                // the socket buffer overflowed, whatever got dropped is in /proc
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    vlog!("[watch] proc connector lost events, rescanning");
+                    self.rescan();
+                }
+                Err(e) => {
+                    vlog!("[watch] proc connector failed ({}), polling /proc instead", e);
+                    self.connector = None;
+                }
+            }
+        }
+    }
+
+    fn start(&mut self) {
+        self.started = true;
+        let mut pid = std::process::id() as pid_t;
+        while pid > 1 && self.ourselves.insert(pid) {
+            pid = get_parent_pid(pid).unwrap_or(0);
+        }
+
+        // listen before the first scan, so nothing starting in between is missed
+        self.connector = match ProcConnector::open() {
+            Ok(connector) => Some(connector),
+            Err(e) => {
+                vlog!("[watch] proc connector unavailable ({}), polling /proc every {:?}", e, self.interval);
+                None
+            }
+        };
+
+        if self.new_only {
+            self.seen = list_pids().into_iter().filter(|&pid| self.filter.matches(pid)).collect();
+            vlog!("[watch] {} matching process(es) already running, skipped", self.seen.len());
+        } else {
+            self.rescan();
+        }
+    }
+
+    fn rescan(&mut self) {
+        let pids: HashSet<pid_t> = list_pids().into_iter().collect();
+        // forget processes that are gone, their pid may come back as a new match
+        self.seen.retain(|pid| pids.contains(pid));
+        for pid in pids {
+            self.consider(pid);
+        }
+    }
+
+    fn consider(&mut self, pid: pid_t) {
+        if self.ourselves.contains(&pid) || self.seen.contains(&pid) || !self.filter.matches(pid) {
+            return;
+        }
+        vlog!("[watch] pid={} matches", pid);
+        self.seen.insert(pid);
+        self.loading.push(pid);
+    }
+
+    fn take_ready(&mut self) -> Option<pid_t> {
+        // died before getting anywhere
+        self.loading.retain(|pid| std::path::Path::new(&format!("/proc/{}", pid)).exists());
+
+        let i = self.loading.iter().position(|&pid| {
+            let bits = get_process_bitness(pid).unwrap_or(usize::BITS);
+            get_module_mapping(pid, &get_libc_path_for(bits)).is_some()
+        })?;
+        Some(self.loading.remove(i))
+    }
+}
+
+// ---------- proc connector ----------
+
+// linux/connector.h and linux/cn_proc.h
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+const PROC_EVENT_NONE: u32 = 0;
+const PROC_EVENT_FORK: u32 = 0x1;
+const PROC_EVENT_EXEC: u32 = 0x2;
+const PROC_EVENT_COMM: u32 = 0x200;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+// struct cn_msg without the payload
+#[repr(C)]
+struct CnMsg {
+    idx: u32,
+    val: u32,
+    seq: u32,
+    ack: u32,
+    len: u16,
+    flags: u16,
+}
+
+#[repr(C)]
+struct ListenRequest {
+    header: libc::nlmsghdr,
+    msg: CnMsg,
+    op: u32,
+}
+
+// Offsets into a received message: nlmsghdr, cn_msg, then struct proc_event
+// { what, cpu, timestamp_ns, event_data }
+const EVENT_OFFSET: usize = size_of::<libc::nlmsghdr>() + size_of::<CnMsg>();
+const EVENT_DATA_OFFSET: usize = 16;
+
+enum ProcEvent {
+    /// Forked, exec'd or renamed, which is when a process can start matching
+    Started(pid_t),
+    Exited(pid_t),
+}
+
+#[derive(Debug)]
+struct ProcConnector {
+    fd: OwnedFd,
+}
+
+impl ProcConnector {
+    // Subscribing needs CAP_NET_ADMIN, the kernel acks the request with an errno
+    fn open() -> io::Result<Self> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_CONNECTOR) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let connector = ProcConnector { fd: unsafe { OwnedFd::from_raw_fd(fd) } };
+
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = CN_IDX_PROC;
+        let ret = unsafe {
+            libc::bind(fd, &addr as *const _ as *const libc::sockaddr, size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let request = ListenRequest {
+            header: libc::nlmsghdr {
+                nlmsg_len: size_of::<ListenRequest>() as u32,
+                nlmsg_type: libc::NLMSG_DONE as u16,
+                nlmsg_flags: 0,
+                nlmsg_seq: 0,
+                nlmsg_pid: std::process::id(),
+            },
+            msg: CnMsg { idx: CN_IDX_PROC, val: CN_VAL_PROC, seq: 0, ack: 0, len: size_of::<u32>() as u16, flags: 0 },
+            op: PROC_CN_MCAST_LISTEN,
+        };
+        let ret = unsafe { libc::send(fd, &request as *const _ as *const c_void, size_of::<ListenRequest>(), 0) };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        connector.wait_for_ack()?;
+        vlog!("[watch] listening to the proc connector");
+        Ok(connector)
+    }
+
+    // Events from other processes may arrive before the ack, those are dropped: the
+    // caller scans /proc right after
+    fn wait_for_ack(&self) -> io::Result<()> {
+        let deadline = Instant::now() + Duration::from_millis(200);
+        let mut buf = [0u8; 4096];
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() || !self.poll(left)? {
+                // old kernels don't ack, assume the subscription went through
+                return Ok(());
+            }
+            let len = self.recv(&mut buf)?;
+            for event in messages(&buf[..len]) {
+                if read_u32(event, 0) == Some(PROC_EVENT_NONE) {
+                    return match read_u32(event, EVENT_DATA_OFFSET) {
+                        Some(0) | None => Ok(()),
+                        Some(err) => Err(Error::from_raw_os_error(err as i32)),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Events received within `timeout`, possibly none
+    fn wait(&self, timeout: Duration) -> io::Result<Vec<ProcEvent>> {
+        let mut events = Vec::new();
+        if !self.poll(timeout)? {
+            return Ok(events);
+        }
+
+        let mut buf = [0u8; 4096];
+        let len = self.recv(&mut buf)?;
+        for event in messages(&buf[..len]) {
+            let data = |index: usize| read_u32(event, EVENT_DATA_OFFSET + index * 4).map(|v| v as pid_t);
+            match read_u32(event, 0) {
+                // parent_pid, parent_tgid, child_pid, child_tgid. A new thread isn't a new process
+                Some(PROC_EVENT_FORK) => {
+                    if let (Some(pid), Some(tgid)) = (data(2), data(3)) {
+                        if pid == tgid {
+                            events.push(ProcEvent::Started(tgid));
+                        }
+                    }
+                }
+                // process_pid, process_tgid
+                Some(PROC_EVENT_EXEC | PROC_EVENT_COMM) => {
+                    if let Some(tgid) = data(1) {
+                        events.push(ProcEvent::Started(tgid));
+                    }
+                }
+                Some(PROC_EVENT_EXIT) => {
+                    if let (Some(pid), Some(tgid)) = (data(0), data(1)) {
+                        if pid == tgid {
+                            events.push(ProcEvent::Exited(tgid));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(events)
+    }
+
+    fn poll(&self, timeout: Duration) -> io::Result<bool> {
+        let mut pfd = libc::pollfd { fd: self.fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let ms = timeout.as_millis().clamp(1, i32::MAX as u128) as i32;
+        match unsafe { libc::poll(&mut pfd, 1, ms) } {
+            ret if ret < 0 && Error::last_os_error().raw_os_error() == Some(libc::EINTR) => Ok(false),
+            ret if ret < 0 => Err(Error::last_os_error()),
+            ret => Ok(ret > 0),
+        }
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let ret = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut c_void, buf.len(), 0) };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+}
+
+// The struct proc_event of every netlink message in `buf`
+fn messages(buf: &[u8]) -> Vec<&[u8]> {
+    let mut events = Vec::new();
+    let mut offset = 0;
+    while let Some(len) = read_u32(buf, offset) {
+        let len = len as usize;
+        if len < EVENT_OFFSET || offset + len > buf.len() {
+            break;
+        }
+        events.push(&buf[offset + EVENT_OFFSET..offset + len]);
+        // NLMSG_ALIGN
+        offset += (len + 3) & !3;
+    }
+    events
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    bytes.get(offset..offset + 4).map(|b| u32::from_ne_bytes(b.try_into().unwrap()))
+}
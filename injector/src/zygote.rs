@@ -0,0 +1,215 @@
+//! Catching an Android app as zygote forks it, before any of the app's own code runs
+//!
+//! Apps are never exec'd: zygote forks and the child is specialized into the app, which
+//! is also when its cmdline turns into the package name. We follow zygote's forks,
+//! watch the cmdline of every new child and keep the one that becomes the package,
+//! everything else is let go again.
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use libc::pid_t;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+
+use crate::error::{InjectError, Result};
+use crate::ptrace::{
+    ptrace_cont, ptrace_detach, ptrace_event_msg, ptrace_interrupt, ptrace_seize, ptrace_set_options, PTRACE_EVENT_STOP,
+};
+use crate::process::list_pids;
+use crate::utils::{get_cmdline, process_name_matches};
+
+const ZYGOTES: [&str; 2] = ["zygote", "zygote64"];
+// Children zygote forks ahead of time for the unspecialized app process pool (Android 10+),
+// an app may be started in one of those instead of a fresh fork
+const USAPS: [&str; 2] = ["usap32", "usap64"];
+// What a child is called before it gets its final name
+const UNSPECIALIZED: &str = "<pre-initialized>";
+
+// How often the cmdline of unspecialized children is checked
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// A zygote, followed for its forks
+    Zygote,
+    /// Not specialized yet, its cmdline is watched
+    Child,
+    /// Interrupted to be detached
+    Releasing,
+    /// Interrupted, it is the package
+    Taking,
+    /// The package, stopped for us
+    Taken,
+}
+
+struct ZygoteTracer<'a> {
+    package: &'a str,
+    tracees: HashMap<pid_t, State>,
+}
+
+/// Wait for `package` to be forked off zygote and specialized. The returned process
+/// is seized and stopped (PTRACE_EVENT_STOP), every other process traced on the way
+/// is detached again
+pub(crate) fn wait_for_package(package: &str) -> Result<pid_t> {
+    let mut tracer = ZygoteTracer { package, tracees: HashMap::new() };
+    let result = tracer.seize_zygotes().and_then(|_| tracer.follow());
+    tracer.release_all(result.as_ref().ok().copied());
+    result
+}
+
+impl ZygoteTracer<'_> {
+    fn seize_zygotes(&mut self) -> Result<()> {
+        let pids = list_pids();
+
+        for &pid in &pids {
+            if ZYGOTES.iter().any(|name| process_name_matches(pid, name)) {
+                ptrace_seize(pid, libc::PTRACE_O_TRACEFORK)?;
+                vlog!("[zygote] following forks of zygote pid={}", pid);
+                self.tracees.insert(pid, State::Zygote);
+            }
+        }
+        if self.tracees.is_empty() {
+            return Err(InjectError::ProcessNotFound("zygote".to_string()));
+        }
+
+        for &pid in &pids {
+            if USAPS.iter().any(|name| process_name_matches(pid, name)) && ptrace_seize(pid, 0).is_ok() {
+                vlog!("[zygote] watching pooled process pid={}", pid);
+                self.tracees.insert(pid, State::Child);
+            }
+        }
+        Ok(())
+    }
+
+    fn follow(&mut self) -> Result<pid_t> {
+        loop {
+            self.handle_events();
+
+            if let Some((&pid, _)) = self.tracees.iter().find(|(_, &state)| state == State::Taken) {
+                return Ok(pid);
+            }
+            if !self.tracees.values().any(|&state| state == State::Zygote) {
+                return Err(InjectError::ProcessNotFound("zygote".to_string()));
+            }
+
+            let children: Vec<pid_t> =
+                self.tracees.iter().filter(|(_, &state)| state == State::Child).map(|(&pid, _)| pid).collect();
+            for pid in children {
+                let Some(name) = get_cmdline(pid).and_then(|args| args.into_iter().next()) else {
+                    continue;
+                };
+                if name == self.package {
+                    vlog!("[zygote] pid={} is {}", pid, self.package);
+                    self.interrupt(pid, State::Taking);
+                    // nothing else needs watching from here on
+                    break;
+                }
+                let unspecialized = name.is_empty()
+                    || name == UNSPECIALIZED
+                    || ZYGOTES.contains(&name.as_str())
+                    || USAPS.contains(&name.as_str());
+                if !unspecialized {
+                    vlog!("[zygote] pid={} turned into {}, letting it go", pid, name);
+                    self.interrupt(pid, State::Releasing);
+                }
+            }
+
+            if !self.tracees.values().any(|&state| state == State::Taking) {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    // Detach from everything but `keep`, waiting for each tracee to stop first
+    fn release_all(&mut self, keep: Option<pid_t>) {
+        let others: Vec<pid_t> = self.tracees.keys().copied().filter(|&pid| Some(pid) != keep).collect();
+        for pid in others {
+            if self.tracees.get(&pid) != Some(&State::Releasing) {
+                self.interrupt(pid, State::Releasing);
+            }
+        }
+
+        while self.tracees.keys().any(|&pid| Some(pid) != keep) {
+            self.handle_events();
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn interrupt(&mut self, pid: pid_t, state: State) {
+        match ptrace_interrupt(pid) {
+            Ok(()) => {
+                self.tracees.insert(pid, state);
+            }
+            // exited meanwhile, its exit is reaped with the other events
+            Err(e) => vlog!("[zygote] interrupting pid={} failed: {}", pid, e),
+        }
+    }
+
+    // Process whatever the tracees reported since the last round, without blocking.
+    // Only our own tracees are waited for, so the host's other children are left alone.
+    // A ptrace request failing means the tracee is dying, its exit shows up later
+    fn handle_events(&mut self) {
+        let pids: Vec<pid_t> = self.tracees.keys().copied().collect();
+        for pid in pids {
+            while let Some(&state) = self.tracees.get(&pid) {
+                let status = match waitpid(Pid::from_raw(pid), Some(WaitPidFlag::__WALL | WaitPidFlag::WNOHANG)) {
+                    Ok(WaitStatus::StillAlive) => break,
+                    Ok(status) => status,
+                    // ECHILD, gone without us seeing it exit
+                    Err(_) => {
+                        self.tracees.remove(&pid);
+                        break;
+                    }
+                };
+
+                match status {
+                    WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                        self.tracees.remove(&pid);
+                        break;
+                    }
+                    WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_FORK) => {
+                        if let Ok(child) = ptrace_event_msg(pid) {
+                            let child = child as pid_t;
+                            // forks during the release are let go right away
+                            let releasing = state == State::Releasing
+                                || self.tracees.values().any(|&s| matches!(s, State::Taking | State::Taken));
+                            vlog!("[zygote] pid={} forked {}", pid, child);
+                            self.tracees.insert(child, if releasing { State::Releasing } else { State::Child });
+                        }
+                        resume(pid, None);
+                    }
+                    WaitStatus::PtraceEvent(_, _, PTRACE_EVENT_STOP) => match state {
+                        State::Releasing => {
+                            let _ = ptrace_detach(pid);
+                            self.tracees.remove(&pid);
+                            break;
+                        }
+                        State::Taking | State::Taken => {
+                            self.tracees.insert(pid, State::Taken);
+                            break;
+                        }
+                        // a fresh child stops once when it is auto-attached, it doesn't
+                        // need to follow forks of its own
+                        State::Child => {
+                            let _ = ptrace_set_options(pid, 0);
+                            resume(pid, None);
+                        }
+                        State::Zygote => resume(pid, None),
+                    },
+                    // signal-delivery-stop, the signal is the tracee's business
+                    WaitStatus::Stopped(_, sig) => resume(pid, Some(sig)),
+                    _ => resume(pid, None),
+                }
+            }
+        }
+    }
+}
+
+fn resume(pid: pid_t, sig: Option<Signal>) {
+    if let Err(e) = ptrace_cont(pid, sig) {
+        vlog!("[zygote] resuming pid={} failed: {}", pid, e);
+    }
+}
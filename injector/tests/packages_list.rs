@@ -0,0 +1,86 @@
+//! packages.list parsing and package-to-process matching, run against a list taken
+//! from a device so it works on any host
+
+use injector::packages::{find_package, parse_packages_list, read_packages_list};
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/packages.list");
+
+#[test]
+fn parses_every_wellformed_line() {
+    let packages = read_packages_list(FIXTURE).unwrap();
+    let names: Vec<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "com.android.providers.telephony",
+            "com.google.android.gms",
+            "com.google.android.gsf",
+            "com.example.app",
+            "com.example.app.helper",
+            "com.android.shell",
+        ]
+    );
+
+    let app = find_package(&packages, "com.example.app").unwrap();
+    assert_eq!(app.uid, 10234);
+    assert!(app.debuggable);
+    assert_eq!(app.data_dir, "/data/user/0/com.example.app");
+    assert_eq!(app.seinfo, "default:targetSdkVersion=34");
+    assert!(!find_package(&packages, "com.google.android.gms").unwrap().debuggable);
+}
+
+#[test]
+fn missing_list_is_an_error() {
+    assert!(read_packages_list(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/missing.list")).is_err());
+    assert!(parse_packages_list("").is_empty());
+}
+
+#[test]
+fn skips_lines_that_dont_fit() {
+    let good = "com.example.app 10234 1 /data/user/0/com.example.app default:targetSdkVersion=34 3003 1 12";
+    let list = [
+        good,
+        // cut short after the uid, and one field short of the seinfo
+        "com.example.short 10300",
+        "com.example.short 10300 0 /data/user/0/com.example.short",
+        // uid not a number, or negative
+        "com.example.nan abc 0 /data/user/0/com.example.nan default none 0 1",
+        "com.example.neg -1 0 /data/user/0/com.example.neg default none 0 1",
+        "",
+        "   ",
+    ]
+    .join("\n");
+
+    let packages = parse_packages_list(&list);
+    assert_eq!(packages.len(), 1);
+    assert_eq!(packages[0].name, "com.example.app");
+    // the five fields we use are enough, the rest is optional
+    assert_eq!(parse_packages_list("com.example.min 10301 0 /data/user/0/com.example.min default").len(), 1);
+}
+
+#[test]
+fn owns_its_processes_for_every_user() {
+    let packages = read_packages_list(FIXTURE).unwrap();
+    let app = find_package(&packages, "com.example.app").unwrap();
+
+    assert!(app.owns_process(10234, "com.example.app"));
+    assert!(app.owns_process(10234, "com.example.app:service"));
+    // same app for a secondary user / work profile
+    assert!(app.owns_process(1_010_234, "com.example.app"));
+
+    assert!(!app.owns_process(10235, "com.example.app"));
+    assert!(!app.owns_process(10234, "com.example.application"));
+    assert!(!app.owns_process(10234, "com.example"));
+}
+
+#[test]
+fn shared_uid_is_split_by_process_name() {
+    let packages = read_packages_list(FIXTURE).unwrap();
+    let gms = find_package(&packages, "com.google.android.gms").unwrap();
+    let gsf = find_package(&packages, "com.google.android.gsf").unwrap();
+    assert_eq!(gms.uid, gsf.uid);
+
+    assert!(gms.owns_process(10145, "com.google.android.gms:snet"));
+    assert!(!gms.owns_process(10145, "com.google.android.gsf"));
+    assert!(gsf.owns_process(10145, "com.google.android.gsf"));
+}
@@ -0,0 +1,57 @@
+//! A target blocked in a syscall has to carry on with it after we ran code on its
+//! thread: no early return with -EINTR, and no starting over either. Only x86 keeps the
+//! restart state visible at the stop, arm restarts a relative sleep from scratch
+#![cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use injector::Injector;
+
+const SLEEP_SECS: u64 = 3;
+
+// /proc/<pid>/syscall starts with the syscall number while blocked in one,
+// -1 outside of a syscall and reads "running" otherwise
+fn current_syscall(pid: u32) -> Option<i64> {
+    let line = fs::read_to_string(format!("/proc/{}/syscall", pid)).ok()?;
+    line.split_whitespace().next()?.parse().ok()
+}
+
+fn blocked_in_syscall(pid: u32) -> bool {
+    current_syscall(pid).is_some_and(|nr| nr >= 0)
+}
+
+#[test]
+fn sleeping_target_keeps_sleeping() {
+    let start = Instant::now();
+    let mut child = Command::new("sleep").arg(SLEEP_SECS.to_string()).spawn().expect("failed to spawn sleep");
+    let pid = child.id();
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !blocked_in_syscall(pid) {
+        assert!(Instant::now() < deadline, "sleep never blocked in its syscall");
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    let mut session = Injector::new(pid as i32).attach().expect("attach failed");
+    let getpid = session.resolve(session.libc_path(), "getpid").expect("getpid not resolved");
+    assert_eq!(session.call(getpid, &[]).expect("remote getpid failed"), pid as u64);
+    session.detach().expect("detach failed");
+
+    thread::sleep(Duration::from_millis(200));
+    assert!(blocked_in_syscall(pid), "target is not back in its sleep after detaching");
+
+    let status = child.wait().expect("wait failed");
+    let elapsed = start.elapsed();
+    assert!(status.success(), "sleep failed: {}", status);
+    assert!(elapsed >= Duration::from_secs(SLEEP_SECS), "woke up early after {:?}", elapsed);
+    // restarting from scratch would add the second spent before attaching
+    assert!(
+        elapsed < Duration::from_millis(SLEEP_SECS * 1000 + 700),
+        "sleep restarted from scratch, took {:?}",
+        elapsed
+    );
+}